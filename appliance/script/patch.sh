@@ -3,6 +3,10 @@
 # the live system during start up of the virtual machine.
 # Where this file is downloaded from is configured within the config
 # file of /etc/warrior4-appliance.toml
+#
+# Increase the version below for every new patch. The service skips patches
+# with a version that is not newer than the one it applied last.
+# warrior4-patch-version: 1
 
 set -e
 
@@ -1,7 +1,14 @@
 #!/bin/sh
 # Payload container pre-start script
+# Copied from https://github.com/ArchiveTeam/Ubuntu-Warrior/blob/develop/startup.sh
 set -e
 
+# Create a blank configuration file if none exists, otherwise do nothing
+touch /root/config.json # https://unix.stackexchange.com/a/343558
+
+# Make sure the container has access to the config file
+chmod 777 /root/config.json
+
 # This directory will be a bind mount to the container's /tmp directory
 mkdir -p /tmp/warrior
 chmod 777 /tmp/warrior
@@ -0,0 +1,39 @@
+#!/bin/sh
+# Appliance manager start script run by supervise-daemon on every start and respawn
+#
+# Binaries replaced by a self-update are kept with the .warrior4-prev suffix
+# until the update succeeds. The manager rolls back an update that did not
+# finish by itself, but not if the new binary fails before it gets that far,
+# so the previous binaries are restored here on the second start.
+
+backup_suffix=warrior4-prev
+backup_dir=/usr/bin
+starts_file=/var/lib/warrior4-appliance/self-update-starts
+
+restore_backups() {
+    set -- "$backup_dir"/*."$backup_suffix"
+
+    if [ ! -e "$1" ]; then
+        rm -f "$starts_file"
+        return 0
+    fi
+
+    starts=$(( $(cat "$starts_file" 2>/dev/null || echo 0) + 1 ))
+
+    if [ "$starts" -lt 2 ]; then
+        echo "$starts" > "$starts_file"
+        return 0
+    fi
+
+    echo "Self-update did not finish, restoring the previous binaries" >&2
+
+    for backup in "$@"; do
+        mv -f "$backup" "${backup%."$backup_suffix"}"
+    done
+
+    rm -f "$starts_file"
+}
+
+restore_backups
+
+exec /usr/bin/warrior4-appliance "$@"
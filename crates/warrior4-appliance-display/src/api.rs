@@ -1,17 +1,43 @@
 //! API for IPC use
+//!
+//! The appliance manager connects to the display and keeps the connection
+//! open. Each message is a JSON object on a single line. Both sides first send
+//! a `hello` message with the protocol version. Every message with a non-zero
+//! ID is answered with an `ack` (or `nack`) message containing that ID.
+//!
+//! After the handshake, the display sends a `get_snapshot` request and the
+//! manager answers with a `snapshot` of what should be on the screen, so a
+//! restarted display does not have to wait for the next status update.
 use serde::{Deserialize, Serialize};
 
+/// Version of the IPC protocol sent in the `hello` message
+pub const PROTOCOL_VERSION: u32 = 2;
+
 /// The JSON object that gets serialized for IPC usage
 ///
 /// Example:
 ///
 /// ```json
-/// {"request": "progress_info", "text": "Hello", "percent": 50}
+/// {"id": 5, "request": "progress_info", "text": "Hello", "percent": 50}
 /// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    /// Sequence number used for acknowledgements or 0 if no reply is wanted
+    pub id: u64,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "request")]
 #[serde(rename_all = "snake_case")]
 pub enum Request {
+    /// Start of the connection
+    Hello { version: u32 },
+    /// The message with the given ID was received
+    Ack { message_id: u64 },
+    /// The message with the given ID was rejected
+    Nack { message_id: u64, error: String },
     /// Information message
     Info { text: String },
     /// Informational message with progress bar update
@@ -27,5 +53,27 @@ pub enum Request {
     /// Error message
     Error { text: String },
     /// Output of a command
-    CommandOutput { text: String }
+    CommandOutput { text: String },
+    /// Ask the manager for a [`Request::Snapshot`] (sent by the display)
+    GetSnapshot,
+    /// Everything the display should currently be showing
+    Snapshot {
+        /// Current message or `None` if no status has been sent yet
+        message: Option<String>,
+        severity: Severity,
+        /// Progress bar value if the message has one
+        progress: Option<u8>,
+        command_output: String,
+    },
+}
+
+/// How important a status message is
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    #[default]
+    Info,
+    Ready,
+    Warning,
+    Error,
 }
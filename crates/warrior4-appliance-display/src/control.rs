@@ -0,0 +1,125 @@
+//! API for the warrior4-appliance control socket
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Default path of the warrior4-appliance control Unix socket
+pub const DEFAULT_SOCKET_PATH: &str = "/run/warrior4-appliance.sock";
+
+/// A command sent to the appliance manager, one JSON object per line
+///
+/// Example:
+///
+/// ```json
+/// {"command": "restart_payload"}
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command")]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    /// Get the current phase, container states, recent errors, and state
+    Status,
+    /// Restart the payload container
+    RestartPayload,
+    /// Run the updater containers (Watchtower run-once)
+    RunUpdater,
+    /// Download and run the patch again
+    RunPatch,
+    /// Restart the machine
+    Reboot,
+    /// Power off the machine
+    Poweroff,
+    /// Select the release channel for patches and updates
+    SetChannel { channel: String },
+}
+
+/// The reply to a [`Request`], one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result")]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Status(Status),
+    /// The command was accepted and is being run
+    Accepted {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Snapshot of the appliance manager
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub phase: Option<String>,
+    pub containers: Vec<ContainerInfo>,
+    pub last_errors: Vec<ErrorInfo>,
+    /// Version of the last versioned patch that was applied
+    #[serde(default)]
+    pub patch_version: Option<u64>,
+    /// Most recent patch runs, newest first
+    #[serde(default)]
+    pub patches: Vec<PatchInfo>,
+    /// Selected release channel
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Release channels that can be selected
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// The manager's state file contents
+    pub state: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerInfo {
+    pub name: String,
+    pub role: String,
+    /// Docker status or `None` if the container does not exist
+    pub status: Option<String>,
+    pub health: Option<String>,
+    pub exit_code: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    /// RFC 3339 timestamp
+    pub time: String,
+    pub phase: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchInfo {
+    /// RFC 3339 timestamp of when the patch was started
+    pub time: String,
+    pub version: Option<u64>,
+    /// Hex SHA-256 hash of the patch file
+    pub sha256: String,
+    /// `running`, `applied`, `failed`, or `refused`
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// Send a request to the control socket and wait for the response
+pub fn send_request(path: &Path, request: &Request) -> anyhow::Result<Response> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut buf = serde_json::to_vec(request)?;
+    buf.push(b'\n');
+    writer.write_all(&buf)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let response = serde_json::from_str::<Response>(&line)?;
+
+    Ok(response)
+}
@@ -0,0 +1,104 @@
+//! History of the IPC requests received by the display
+
+use std::{collections::VecDeque, fmt::Display};
+
+use chrono::{DateTime, Local};
+
+use crate::{IPCRequest, Severity};
+
+/// Maximum number of events kept
+pub const MAX_HISTORY_LENGTH: usize = 500;
+
+/// Type of the request that was received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Info,
+    Progress,
+    Ready,
+    Warning,
+    Error,
+    CommandOutput,
+    Snapshot,
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventKind::Info => write!(f, "info"),
+            EventKind::Progress => write!(f, "progress"),
+            EventKind::Ready => write!(f, "ready"),
+            EventKind::Warning => write!(f, "warning"),
+            EventKind::Error => write!(f, "error"),
+            EventKind::CommandOutput => write!(f, "output"),
+            EventKind::Snapshot => write!(f, "snapshot"),
+        }
+    }
+}
+
+/// A request that was received
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub time: DateTime<Local>,
+    pub severity: Severity,
+    pub kind: EventKind,
+    pub text: String,
+}
+
+impl HistoryEntry {
+    /// Returns an entry for the request or `None` if it is not shown to the user
+    pub fn from_request(request: &IPCRequest) -> Option<Self> {
+        let (severity, kind, text) = match request {
+            IPCRequest::Info { text } => (Severity::Info, EventKind::Info, text.clone()),
+            IPCRequest::ProgressInfo { text, percent } => (
+                Severity::Info,
+                EventKind::Progress,
+                format!("{text} ({percent}%)"),
+            ),
+            IPCRequest::ReadyInfo { text } => (Severity::Ready, EventKind::Ready, text.clone()),
+            IPCRequest::Warning { text } => (Severity::Warning, EventKind::Warning, text.clone()),
+            IPCRequest::Error { text } => (Severity::Error, EventKind::Error, text.clone()),
+            IPCRequest::CommandOutput { text } if !text.is_empty() => {
+                (Severity::Info, EventKind::CommandOutput, text.clone())
+            }
+            IPCRequest::Snapshot {
+                message: Some(message),
+                severity,
+                ..
+            } => (*severity, EventKind::Snapshot, message.clone()),
+            IPCRequest::CommandOutput { .. }
+            | IPCRequest::Snapshot { .. }
+            | IPCRequest::Hello { .. }
+            | IPCRequest::Ack { .. }
+            | IPCRequest::Nack { .. }
+            | IPCRequest::GetSnapshot => return None,
+        };
+
+        Some(Self {
+            time: Local::now(),
+            severity,
+            kind,
+            text,
+        })
+    }
+}
+
+/// Bounded list of events, oldest first
+#[derive(Debug, Default)]
+pub struct MessageHistory {
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl MessageHistory {
+    /// Add an event, dropping the oldest one if the history is full
+    pub fn push(&mut self, entry: HistoryEntry) {
+        if self.entries.len() >= MAX_HISTORY_LENGTH {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> {
+        self.entries.iter()
+    }
+}
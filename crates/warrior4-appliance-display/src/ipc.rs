@@ -1,13 +1,13 @@
 //! IPC socket to allow the appliance management program to talk to us
 
 use std::{
-    io::{BufRead, BufReader, BufWriter},
+    io::{BufRead, BufReader, BufWriter, Write},
     net::{SocketAddr, TcpListener, TcpStream},
     sync::mpsc::Sender,
     time::Duration,
 };
 
-use crate::api::Request;
+use warrior4_appliance_display::{IPCMessage as Message, IPCRequest as Request, PROTOCOL_VERSION};
 
 pub fn run(channel: Sender<Request>, address: SocketAddr) -> anyhow::Result<()> {
     let listener = TcpListener::bind(address)?;
@@ -29,10 +29,39 @@ pub fn run(channel: Sender<Request>, address: SocketAddr) -> anyhow::Result<()>
 }
 
 fn handle_client(stream: TcpStream, channel: Sender<Request>) -> anyhow::Result<()> {
+    stream.set_nodelay(true)?;
+
     let mut reader = BufReader::new(stream.try_clone()?);
-    let mut _writer = BufWriter::new(stream);
+    let mut writer = BufWriter::new(stream);
     let mut buf = String::new();
 
+    reader.read_line(&mut buf)?;
+
+    match serde_json::from_str::<Message>(&buf) {
+        Ok(Message {
+            request: Request::Hello { version },
+            ..
+        }) if version == PROTOCOL_VERSION => {
+            send_message(&mut writer, 0, Request::Hello { version })?;
+            send_message(&mut writer, 0, Request::GetSnapshot)?;
+        }
+        Ok(message) => {
+            let error = format!("expected hello version {PROTOCOL_VERSION}");
+            send_message(
+                &mut writer,
+                0,
+                Request::Nack {
+                    message_id: message.id,
+                    error,
+                },
+            )?;
+            anyhow::bail!("unexpected handshake {message:?}");
+        }
+        Err(error) => {
+            anyhow::bail!("invalid handshake: {error}");
+        }
+    }
+
     loop {
         buf.clear();
         let amount = reader.read_line(&mut buf)?;
@@ -41,9 +70,57 @@ fn handle_client(stream: TcpStream, channel: Sender<Request>) -> anyhow::Result<
             break;
         }
 
-        let doc = serde_json::from_str::<Request>(&buf)?;
-        channel.send(doc)?;
+        let message = match serde_json::from_str::<Message>(&buf) {
+            Ok(message) => message,
+            Err(error) => {
+                // Reply to malformed messages but keep the connection
+                let id = serde_json::from_str::<serde_json::Value>(&buf)
+                    .ok()
+                    .and_then(|value| value.get("id").and_then(|id| id.as_u64()))
+                    .unwrap_or_default();
+                let error = error.to_string();
+                eprintln!("{error}");
+                send_message(
+                    &mut writer,
+                    0,
+                    Request::Nack {
+                        message_id: id,
+                        error,
+                    },
+                )?;
+                continue;
+            }
+        };
+
+        match message.request {
+            Request::Hello { .. }
+            | Request::Ack { .. }
+            | Request::Nack { .. }
+            | Request::GetSnapshot => {}
+            request => {
+                channel.send(request)?;
+            }
+        }
+
+        if message.id != 0 {
+            send_message(
+                &mut writer,
+                0,
+                Request::Ack {
+                    message_id: message.id,
+                },
+            )?;
+        }
     }
 
     Ok(())
 }
+
+fn send_message<W: Write>(writer: &mut W, id: u64, request: Request) -> anyhow::Result<()> {
+    let mut buf = serde_json::to_vec(&Message { id, request })?;
+    buf.push(b'\n');
+    writer.write_all(&buf)?;
+    writer.flush()?;
+
+    Ok(())
+}
@@ -1,3 +1,5 @@
 /// Warrior virtual appliance information display
 mod api;
-pub use api::Request as IPCRequest;
+pub mod control;
+pub mod history;
+pub use api::{Message as IPCMessage, Request as IPCRequest, Severity, PROTOCOL_VERSION};
@@ -0,0 +1,603 @@
+//! Dialog that shows the end of a log file and follows new lines
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use cursive::{
+    theme::{BaseColor, Color, Effect, Style},
+    utils::markup::StyledString,
+    view::{Nameable, Resizable, ScrollStrategy, Scrollable},
+    views::{Dialog, EditView, NamedView, ScrollView, TextView},
+    Cursive, Vec2,
+};
+
+/// Maximum number of lines kept in memory
+const MAX_LINES: usize = 5000;
+/// Maximum number of bytes read when opening the file or catching up
+const MAX_READ_BYTES: u64 = 512 * 1024;
+/// Lines longer than this are split
+const MAX_LINE_BYTES: usize = 64 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static DIALOG_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Reads lines appended to a file
+struct LogTail {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    offset: u64,
+    partial: Vec<u8>,
+    lines: VecDeque<String>,
+    error: Option<String>,
+}
+
+impl LogTail {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file: None,
+            inode: 0,
+            offset: 0,
+            partial: Vec::new(),
+            lines: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// Read new lines and returns whether anything changed
+    fn poll(&mut self) -> bool {
+        match self.read_new_lines() {
+            Ok(changed) => {
+                let had_error = self.error.take().is_some();
+                changed || had_error
+            }
+            Err(error) => {
+                let error = error.to_string();
+                let changed = self.error.as_ref() != Some(&error);
+                self.error = Some(error);
+                self.file = None;
+                changed
+            }
+        }
+    }
+
+    fn read_new_lines(&mut self) -> std::io::Result<bool> {
+        let metadata = std::fs::metadata(&self.path)?;
+        let length = metadata.len();
+
+        if self.file.is_none() || metadata.ino() != self.inode {
+            let is_first_open = self.inode == 0;
+
+            self.file = Some(File::open(&self.path)?);
+            self.inode = metadata.ino();
+            self.partial.clear();
+
+            if is_first_open {
+                self.offset = length.saturating_sub(MAX_READ_BYTES);
+                if self.offset > 0 {
+                    self.skip_to_next_line()?;
+                }
+            } else {
+                self.push_marker("log file was replaced");
+                self.offset = 0;
+            }
+        } else if length < self.offset {
+            // logrotate's copytruncate empties the file in place
+            self.push_marker("log file was truncated");
+            self.offset = 0;
+            self.partial.clear();
+        }
+
+        if length == self.offset {
+            return Ok(false);
+        }
+
+        if length - self.offset > MAX_READ_BYTES {
+            self.push_marker(&format!(
+                "skipped {} bytes",
+                length - self.offset - MAX_READ_BYTES
+            ));
+            self.offset = length - MAX_READ_BYTES;
+            self.partial.clear();
+            self.skip_to_next_line()?;
+        }
+
+        let mut buf = Vec::new();
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(self.offset))?;
+        file.take(length - self.offset).read_to_end(&mut buf)?;
+        self.offset += buf.len() as u64;
+
+        for byte in buf {
+            if byte == b'\n' {
+                self.push_partial();
+            } else {
+                self.partial.push(byte);
+
+                if self.partial.len() >= MAX_LINE_BYTES {
+                    self.push_partial();
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Move the offset past the next newline so a partial line isn't shown
+    fn skip_to_next_line(&mut self) -> std::io::Result<()> {
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let amount = file.read(&mut buf)?;
+
+            if amount == 0 {
+                return Ok(());
+            }
+
+            if let Some(position) = buf[..amount].iter().position(|&b| b == b'\n') {
+                self.offset += position as u64 + 1;
+                return Ok(());
+            }
+
+            self.offset += amount as u64;
+        }
+    }
+
+    fn push_partial(&mut self) {
+        let line = String::from_utf8_lossy(&self.partial)
+            .trim_end_matches('\r')
+            .to_string();
+        self.partial.clear();
+        self.push_line(line);
+    }
+
+    fn push_marker(&mut self, text: &str) {
+        self.push_line(format!("--- {text} ---"));
+    }
+
+    fn push_line(&mut self, line: String) {
+        if self.lines.len() >= MAX_LINES {
+            self.lines.pop_front();
+        }
+
+        self.lines.push_back(line);
+    }
+}
+
+/// State shared between the dialog and the thread following the file
+struct LogViewer {
+    tail: LogTail,
+    query: String,
+    /// Line index of the selected search match
+    current_match: Option<usize>,
+}
+
+impl LogViewer {
+    /// Returns the line indices that contain the search query
+    fn matches(&self) -> Vec<usize> {
+        if self.query.is_empty() {
+            return Vec::new();
+        }
+
+        let query = self.query.to_ascii_lowercase();
+
+        self.tail
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_index, line)| line.to_ascii_lowercase().contains(&query))
+            .map(|(index, _line)| index)
+            .collect()
+    }
+
+    fn to_styled_string(&self) -> StyledString {
+        let mut content = StyledString::new();
+
+        if let Some(error) = &self.tail.error {
+            content.append_styled(
+                format!("{error}\n"),
+                Style::from(Color::Dark(BaseColor::Red)),
+            );
+        }
+
+        let query = self.query.to_ascii_lowercase();
+
+        for (index, line) in self.tail.lines.iter().enumerate() {
+            let style = line_style(line);
+            let highlight = if self.current_match == Some(index) {
+                style.combine(Effect::Reverse).combine(Effect::Bold)
+            } else {
+                style.combine(Effect::Reverse)
+            };
+
+            append_highlighted(&mut content, line, &query, style, highlight);
+            content.append_plain("\n");
+        }
+
+        content
+    }
+}
+
+/// Append the line with occurrences of the query (in lowercase) highlighted
+fn append_highlighted(
+    content: &mut StyledString,
+    line: &str,
+    query: &str,
+    style: Style,
+    highlight: Style,
+) {
+    if query.is_empty() {
+        content.append_styled(line, style);
+        return;
+    }
+
+    // ASCII lowercase keeps byte offsets the same as the original line
+    let lowercase = line.to_ascii_lowercase();
+    let mut position = 0;
+
+    while let Some(found) = lowercase[position..].find(query) {
+        let start = position + found;
+        let end = start + query.len();
+
+        content.append_styled(&line[position..start], style);
+        content.append_styled(&line[start..end], highlight);
+        position = end;
+    }
+
+    content.append_styled(&line[position..], style);
+}
+
+/// Returns the colour for a line based on the log level words in it
+fn line_style(line: &str) -> Style {
+    let lowercase = line.to_ascii_lowercase();
+
+    if ["error", "fatal", "panic", "crit", "emerg", "alert", "fail"]
+        .iter()
+        .any(|word| lowercase.contains(word))
+    {
+        Style::from(Color::Dark(BaseColor::Red))
+    } else if lowercase.contains("warn") {
+        Style::from(Color::Dark(BaseColor::Yellow))
+    } else if line.starts_with("--- ") {
+        Style::from(Effect::Dim)
+    } else {
+        Style::inherit_parent()
+    }
+}
+
+/// Shows a dialog window following the end of a file
+pub fn show_log_dialog(path: &Path, cursive: &mut Cursive) {
+    let id = DIALOG_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let text_name = format!("log_text_view_{id}");
+    let scroll_name = format!("log_scroll_view_{id}");
+
+    let viewer = Arc::new(Mutex::new(LogViewer {
+        tail: LogTail::new(path),
+        query: String::new(),
+        current_match: None,
+    }));
+    viewer.lock().unwrap().tail.poll();
+
+    let text_view = TextView::new(viewer.lock().unwrap().to_styled_string())
+        .no_wrap()
+        .with_name(&text_name)
+        .scrollable()
+        .scroll_x(true)
+        .scroll_strategy(ScrollStrategy::StickToBottom)
+        .with_name(&scroll_name)
+        .full_screen();
+
+    let find_viewer = viewer.clone();
+    let find_names = (text_name.clone(), scroll_name.clone());
+    let next_viewer = viewer.clone();
+    let next_names = (text_name.clone(), scroll_name.clone());
+    let follow_scroll_name = scroll_name.clone();
+
+    cursive.add_layer(
+        Dialog::around(text_view)
+            .title(path.to_string_lossy())
+            .button("Find...", move |c| {
+                show_find_dialog(c, find_viewer.clone(), find_names.clone());
+            })
+            .button("Next match", move |c| {
+                select_next_match(c, &next_viewer, &next_names.0, &next_names.1);
+            })
+            .button("Follow", move |c| {
+                c.call_on_name(
+                    &follow_scroll_name,
+                    |view: &mut ScrollView<NamedView<TextView>>| {
+                        view.set_scroll_strategy(ScrollStrategy::StickToBottom);
+                    },
+                );
+            })
+            .dismiss_button("Close"),
+    );
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let cb_sink = cursive.cb_sink().clone();
+
+    std::thread::spawn(move || {
+        while !closed.load(Ordering::Relaxed) {
+            std::thread::sleep(POLL_INTERVAL);
+
+            let changed = viewer.lock().unwrap().tail.poll();
+            let viewer = viewer.clone();
+            let closed = closed.clone();
+            let text_name = text_name.clone();
+
+            let result = cb_sink.send(Box::new(move |c| {
+                if c.find_name::<TextView>(&text_name).is_none() {
+                    closed.store(true, Ordering::Relaxed);
+                } else if changed {
+                    let content = viewer.lock().unwrap().to_styled_string();
+                    c.call_on_name(&text_name, |view: &mut TextView| {
+                        view.set_content(content);
+                    });
+                }
+            }));
+
+            if result.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Ask for text to search for
+fn show_find_dialog(cursive: &mut Cursive, viewer: Arc<Mutex<LogViewer>>, names: (String, String)) {
+    let query = viewer.lock().unwrap().query.clone();
+
+    let submit = move |c: &mut Cursive, text: &str| {
+        c.pop_layer();
+
+        {
+            let mut viewer = viewer.lock().unwrap();
+            viewer.query = text.to_string();
+            viewer.current_match = None;
+        }
+
+        select_next_match(c, &viewer, &names.0, &names.1);
+    };
+
+    cursive.add_layer(
+        Dialog::around(
+            EditView::new()
+                .content(query)
+                .on_submit(submit)
+                .fixed_width(40),
+        )
+        .title("Find")
+        .dismiss_button("Cancel"),
+    );
+}
+
+/// Scroll to the search match before the selected one, starting from the end
+fn select_next_match(
+    cursive: &mut Cursive,
+    viewer: &Mutex<LogViewer>,
+    text_name: &str,
+    scroll_name: &str,
+) {
+    let (content, line) = {
+        let mut viewer = viewer.lock().unwrap();
+        let matches = viewer.matches();
+
+        let next = match viewer.current_match {
+            Some(current) => matches
+                .iter()
+                .rev()
+                .find(|&&index| index < current)
+                .or(matches.last()),
+            None => matches.last(),
+        }
+        .copied();
+
+        viewer.current_match = next;
+
+        (viewer.to_styled_string(), next)
+    };
+
+    cursive.call_on_name(text_name, |view: &mut TextView| {
+        view.set_content(content);
+    });
+
+    let error_offset = usize::from(viewer.lock().unwrap().tail.error.is_some());
+
+    cursive.call_on_name(
+        scroll_name,
+        |view: &mut ScrollView<NamedView<TextView>>| match line {
+            Some(line) => {
+                view.set_scroll_strategy(ScrollStrategy::KeepRow);
+                view.set_offset(Vec2::new(0, line + error_offset));
+            }
+            None => {
+                view.set_scroll_strategy(ScrollStrategy::StickToBottom);
+            }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Returns a new empty directory for the test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "warrior4-appliance-display-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    fn lines(tail: &LogTail) -> Vec<&str> {
+        tail.lines.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_follow_appended_lines() {
+        let dir = test_dir("append");
+        let path = dir.join("log");
+        append(&path, "one\r\ntwo\nthr");
+
+        let mut tail = LogTail::new(&path);
+        assert!(tail.poll());
+        assert_eq!(lines(&tail), ["one", "two"]);
+        assert!(!tail.poll());
+
+        append(&path, "ee\nfour\n");
+        assert!(tail.poll());
+        assert_eq!(lines(&tail), ["one", "two", "three", "four"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_truncation() {
+        let dir = test_dir("truncate");
+        let path = dir.join("log");
+        append(&path, "old line\nanother old line\n");
+
+        let mut tail = LogTail::new(&path);
+        tail.poll();
+
+        // Like logrotate's copytruncate
+        File::create(&path).unwrap();
+        append(&path, "new\n");
+        assert!(tail.poll());
+        assert_eq!(
+            lines(&tail),
+            [
+                "old line",
+                "another old line",
+                "--- log file was truncated ---",
+                "new"
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = test_dir("rotate");
+        let path = dir.join("log");
+        append(&path, "before\n");
+
+        let mut tail = LogTail::new(&path);
+        tail.poll();
+
+        std::fs::rename(&path, dir.join("log.1")).unwrap();
+        append(&path, "after rotation, a longer line\n");
+        assert!(tail.poll());
+        assert_eq!(
+            lines(&tail),
+            [
+                "before",
+                "--- log file was replaced ---",
+                "after rotation, a longer line"
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file() {
+        let dir = test_dir("missing");
+        let path = dir.join("log");
+
+        let mut tail = LogTail::new(&path);
+        assert!(tail.poll());
+        assert!(tail.error.is_some());
+        assert!(!tail.poll());
+
+        append(&path, "created\n");
+        assert!(tail.poll());
+        assert!(tail.error.is_none());
+        assert_eq!(lines(&tail), ["created"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_large_file_starts_at_line() {
+        let dir = test_dir("large");
+        let path = dir.join("log");
+        let line = "x".repeat(999);
+        let count = MAX_READ_BYTES as usize / 1000 + 10;
+        append(&path, &format!("{line}\n").repeat(count));
+
+        let mut tail = LogTail::new(&path);
+        tail.poll();
+
+        // Only whole lines within the last MAX_READ_BYTES are shown
+        assert_eq!(tail.lines.len(), MAX_READ_BYTES as usize / 1000);
+        assert!(tail.lines.iter().all(|text| *text == line));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_line_cap() {
+        let dir = test_dir("cap");
+        let path = dir.join("log");
+        let text = (0..MAX_LINES + 10)
+            .map(|number| format!("{number}\n"))
+            .collect::<String>();
+        append(&path, &text);
+
+        let mut tail = LogTail::new(&path);
+        tail.poll();
+
+        assert_eq!(tail.lines.len(), MAX_LINES);
+        assert_eq!(tail.lines.front().unwrap(), "10");
+        assert_eq!(tail.lines.back().unwrap(), &(MAX_LINES + 9).to_string());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_long_line_is_split() {
+        let dir = test_dir("long");
+        let path = dir.join("log");
+        append(&path, &format!("{}\n", "y".repeat(MAX_LINE_BYTES + 5)));
+
+        let mut tail = LogTail::new(&path);
+        tail.poll();
+
+        assert_eq!(tail.lines.len(), 2);
+        assert_eq!(tail.lines[0].len(), MAX_LINE_BYTES);
+        assert_eq!(tail.lines[1], "yyyyy");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,26 +1,37 @@
 //! Entry point for the virtual appliance information display
 //!
-mod api;
 mod ipc;
+mod log_view;
+mod network_speed;
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+    time::Duration,
+};
 
-use std::{net::SocketAddr, path::Path, sync::mpsc::Receiver, time::Duration};
-
-use api::Request;
 use clap::Parser;
 use cursive::{
     direction::Orientation,
     event::Key,
     menu::Tree,
     reexports::crossbeam_channel::Sender,
-    theme::{Effect, Style},
+    theme::{BaseColor, Color, Effect, Style},
     utils::markup::StyledString,
     view::{Nameable, Scrollable},
     views::{
-        Dialog, HideableView, LayerPosition, LinearLayout, NamedView, Panel, ProgressBar, TextView,
+        Dialog, DummyView, HideableView, LayerPosition, LinearLayout, NamedView, Panel,
+        ProgressBar, SelectView, TextView,
     },
     Cursive,
 };
 use vt::{Console, VtNumber};
+use warrior4_appliance_display::{
+    control,
+    history::{HistoryEntry, MessageHistory},
+    IPCRequest as Request, Severity,
+};
 
 static COMMON_TITLE: &str = "ArchiveTeam Warrior 4th Edition";
 static INFO_TEXT_PANEL: &str = "info_text_panel";
@@ -28,6 +39,7 @@ static INFO_TEXT_VIEW: &str = "info_text_view";
 static INFO_PROGRESS_BAR: &str = "info_progress_bar";
 static INFO_PROGRESS_BAR_HIDEABLE: &str = "info_progress_bar_hideable";
 static COMMAND_OUTPUT_TEXT_VIEW: &str = "command_output_text_view";
+static EVENT_HISTORY_TEXT_VIEW: &str = "event_history_text_view";
 
 /// Command line arguments
 #[derive(Parser, Debug)]
@@ -40,6 +52,10 @@ struct Args {
     /// Switch to and run on the virtual terminal (1 for tty1)
     #[arg(short, long)]
     vt: Option<u8>,
+
+    /// Path of the appliance manager control socket
+    #[arg(long, default_value = control::DEFAULT_SOCKET_PATH)]
+    control_socket: PathBuf,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -50,10 +66,11 @@ fn main() -> anyhow::Result<()> {
     }
 
     let mut cursive = cursive::default();
+    cursive.set_user_data(MessageHistory::default());
 
-    add_status_menu(&mut cursive);
+    add_status_menu(&mut cursive, &args.control_socket);
     add_logs_menu(&mut cursive);
-    add_actions_menu(&mut cursive);
+    add_actions_menu(&mut cursive, &args.control_socket);
     add_help(&mut cursive);
     add_info_panel(&mut cursive);
     set_up_ipc(&mut cursive, args.ipc_address);
@@ -115,6 +132,14 @@ fn process_callbacks(
 }
 
 fn handle_ipc_event(ipc_event: Request, cursive_sender: CursiveSender) -> anyhow::Result<()> {
+    if let Some(entry) = HistoryEntry::from_request(&ipc_event) {
+        cursive_sender
+            .send(Box::new(|cursive| {
+                cursive.with_user_data(|history: &mut MessageHistory| history.push(entry));
+            }))
+            .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+    }
+
     match ipc_event {
         Request::ProgressInfo { text, percent } => {
             cursive_sender
@@ -123,13 +148,31 @@ fn handle_ipc_event(ipc_event: Request, cursive_sender: CursiveSender) -> anyhow
                 }))
                 .map_err(|e| anyhow::anyhow!(e.to_string()))?;
         }
-        Request::ReadyInfo { text }
-        | Request::Info { text }
-        | Request::Warning { text }
-        | Request::Error { text } => {
+        Request::ReadyInfo { text } => {
+            cursive_sender
+                .send(Box::new(|cursive| {
+                    show_message(cursive, text, Severity::Ready);
+                }))
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        }
+        Request::Info { text } => {
             cursive_sender
                 .send(Box::new(|cursive| {
-                    show_message(cursive, text);
+                    show_message(cursive, text, Severity::Info);
+                }))
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        }
+        Request::Warning { text } => {
+            cursive_sender
+                .send(Box::new(|cursive| {
+                    show_message(cursive, text, Severity::Warning);
+                }))
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        }
+        Request::Error { text } => {
+            cursive_sender
+                .send(Box::new(|cursive| {
+                    show_message(cursive, text, Severity::Error);
                 }))
                 .map_err(|e| anyhow::anyhow!(e.to_string()))?;
         }
@@ -140,16 +183,47 @@ fn handle_ipc_event(ipc_event: Request, cursive_sender: CursiveSender) -> anyhow
                 }))
                 .map_err(|e| anyhow::anyhow!(e.to_string()))?;
         }
+        Request::Snapshot {
+            message,
+            severity,
+            progress,
+            command_output,
+        } => {
+            cursive_sender
+                .send(Box::new(move |cursive| {
+                    match (message, progress) {
+                        (Some(text), Some(percent)) => show_progress(cursive, text, percent),
+                        (Some(text), None) => show_message(cursive, text, severity),
+                        (None, _) => {}
+                    }
+                    show_command_output(cursive, command_output);
+                }))
+                .map_err(|e| anyhow::anyhow!(e.to_string()))?;
+        }
+        Request::Hello { .. }
+        | Request::Ack { .. }
+        | Request::Nack { .. }
+        | Request::GetSnapshot => {}
     }
 
     Ok(())
 }
 
 /// Add the Status menu item
-fn add_status_menu(cursive: &mut Cursive) {
+fn add_status_menu(cursive: &mut Cursive, control_socket: &Path) {
+    let socket1 = control_socket.to_path_buf();
+    let socket2 = control_socket.to_path_buf();
+
     cursive.menubar().add_subtree(
         "Status",
         Tree::new()
+            .leaf("Appliance manager", move |c| {
+                show_manager_status_dialog(&socket1, c);
+            })
+            .leaf("Event history", show_event_history_dialog)
+            .leaf("Patch history", move |c| {
+                show_patch_history_dialog(&socket2, c);
+            })
             .leaf("IP address", |c| {
                 show_command_dialog(&["ip", "addr", "show"], c);
             })
@@ -161,7 +235,8 @@ fn add_status_menu(cursive: &mut Cursive) {
             })
             .leaf("Filesystem usage", |c| {
                 show_command_dialog(&["df", "-h"], c);
-            }),
+            })
+            .leaf("Network speed", network_speed::show_network_speed_dialog),
     );
 }
 
@@ -171,27 +246,47 @@ fn add_logs_menu(cursive: &mut Cursive) {
         "Logs",
         Tree::new()
             .leaf("System", |c| {
-                show_log_dialog(Path::new("/var/log/messages"), c);
+                log_view::show_log_dialog(Path::new("/var/log/messages"), c);
             })
             .leaf("Docker", |c| {
-                show_log_dialog(Path::new("/var/log/docker.log"), c);
+                log_view::show_log_dialog(Path::new("/var/log/docker.log"), c);
             })
             .leaf("Warrior appliance", |c| {
-                show_log_dialog(Path::new("/var/log/warrior4-appliance.log"), c);
+                log_view::show_log_dialog(Path::new("/var/log/warrior4-appliance.log"), c);
             }),
     );
 }
 
 /// Add the actions menu item
-fn add_actions_menu(cursive: &mut Cursive) {
+fn add_actions_menu(cursive: &mut Cursive, control_socket: &Path) {
+    let socket1 = control_socket.to_path_buf();
+    let socket2 = control_socket.to_path_buf();
+    let socket3 = control_socket.to_path_buf();
+    let socket4 = control_socket.to_path_buf();
+    let socket5 = control_socket.to_path_buf();
+    let socket6 = control_socket.to_path_buf();
+
     cursive.menubar().add_subtree(
         "Actions",
         Tree::new()
-            .leaf("Restart...", |c| {
-                show_action_dialog("reboot", c);
+            .leaf("Restart warrior container...", move |c| {
+                show_action_dialog("restart_payload", &socket1, c);
+            })
+            .leaf("Check for container updates...", move |c| {
+                show_action_dialog("run_updater", &socket2, c);
             })
-            .leaf("Shut down...", |c| {
-                show_action_dialog("poweroff", c);
+            .leaf("Check for system patches...", move |c| {
+                show_action_dialog("run_patch", &socket3, c);
+            })
+            .leaf("Release channel...", move |c| {
+                show_channel_dialog(&socket6, c);
+            })
+            .delimiter()
+            .leaf("Restart...", move |c| {
+                show_action_dialog("reboot", &socket4, c);
+            })
+            .leaf("Shut down...", move |c| {
+                show_action_dialog("poweroff", &socket5, c);
             }),
     );
 }
@@ -231,11 +326,9 @@ fn add_info_panel(cursive: &mut Cursive) {
     );
 }
 
-/// Update the message displayed to the given text
-fn show_message(cursive: &mut Cursive, text: String) {
-    cursive.call_on_name(INFO_TEXT_VIEW, |view: &mut TextView| {
-        view.set_content(text);
-    });
+/// Update the message displayed to the given text and severity
+fn show_message(cursive: &mut Cursive, text: String, severity: Severity) {
+    set_info_text(cursive, text, severity);
 
     cursive.call_on_name(
         INFO_PROGRESS_BAR_HIDEABLE,
@@ -247,9 +340,7 @@ fn show_message(cursive: &mut Cursive, text: String) {
 
 /// Update the message displayed to the given text and progress bar value
 fn show_progress(cursive: &mut Cursive, text: String, percent: u8) {
-    cursive.call_on_name(INFO_TEXT_VIEW, |view: &mut TextView| {
-        view.set_content(text);
-    });
+    set_info_text(cursive, text, Severity::Info);
 
     cursive.call_on_name(
         INFO_PROGRESS_BAR_HIDEABLE,
@@ -263,6 +354,55 @@ fn show_progress(cursive: &mut Cursive, text: String, percent: u8) {
     });
 }
 
+/// Set the info panel title and text styled for the severity
+fn set_info_text(cursive: &mut Cursive, text: String, severity: Severity) {
+    let style = severity_style(severity);
+
+    let title = match severity_label(severity) {
+        Some(label) => StyledString::styled(format!("{COMMON_TITLE} - {label}"), style),
+        None => StyledString::plain(COMMON_TITLE),
+    };
+
+    cursive.call_on_name(INFO_TEXT_PANEL, |view: &mut Panel<LinearLayout>| {
+        view.set_title(title);
+    });
+
+    let mut content = StyledString::new();
+
+    if let Some(label) = severity_label(severity) {
+        content.append_styled(
+            format!("[{}]\n\n", label.to_uppercase()),
+            style.combine(Effect::Bold),
+        );
+    }
+
+    content.append_styled(text, style);
+
+    cursive.call_on_name(INFO_TEXT_VIEW, |view: &mut TextView| {
+        view.set_content(content);
+    });
+}
+
+/// Returns the text colour for messages of the given severity
+fn severity_style(severity: Severity) -> Style {
+    match severity {
+        Severity::Info => Style::inherit_parent(),
+        Severity::Ready => Style::from(Color::Dark(BaseColor::Green)),
+        Severity::Warning => Style::from(Color::Dark(BaseColor::Yellow)),
+        Severity::Error => Style::from(Color::Dark(BaseColor::Red)),
+    }
+}
+
+/// Returns the name shown for messages of the given severity
+fn severity_label(severity: Severity) -> Option<&'static str> {
+    match severity {
+        Severity::Info => None,
+        Severity::Ready => Some("Ready"),
+        Severity::Warning => Some("Warning"),
+        Severity::Error => Some("Error"),
+    }
+}
+
 /// Update the command output displayed to the given text
 fn show_command_output(cursive: &mut Cursive, text: String) {
     cursive.call_on_name(COMMAND_OUTPUT_TEXT_VIEW, |view: &mut TextView| {
@@ -291,18 +431,6 @@ fn is_current_info_layer(cursive: &mut Cursive) -> bool {
     }
 }
 
-/// Shows a dialog window containing the contents of a file
-fn show_log_dialog(path: &Path, cursive: &mut Cursive) {
-    let title = path.to_string_lossy();
-    let content = std::fs::read_to_string(path).unwrap_or_else(|e| e.to_string());
-
-    cursive.add_layer(
-        Dialog::around(TextView::new(content).no_wrap().scrollable().scroll_x(true))
-            .title(title)
-            .dismiss_button("Close"),
-    );
-}
-
 /// Shows a dialog window containing the output of a command
 fn show_command_dialog(args: &[&str], cursive: &mut Cursive) {
     let title = args.join(" ");
@@ -326,28 +454,245 @@ fn show_command_dialog(args: &[&str], cursive: &mut Cursive) {
     );
 }
 
+/// Shows a dialog window containing the appliance manager status
+fn show_manager_status_dialog(control_socket: &Path, cursive: &mut Cursive) {
+    request_status(cursive, control_socket, |c, status| {
+        let content = match status {
+            Ok(status) => format_manager_status(&status),
+            Err(message) => message,
+        };
+
+        c.add_layer(
+            Dialog::around(TextView::new(content).no_wrap().scrollable().scroll_x(true))
+                .title("Appliance manager")
+                .dismiss_button("Close"),
+        );
+    });
+}
+
+/// Shows a dialog window containing the patches run by the appliance manager
+fn show_patch_history_dialog(control_socket: &Path, cursive: &mut Cursive) {
+    request_status(cursive, control_socket, |c, status| {
+        let content = match status {
+            Ok(status) => format_patch_history(&status),
+            Err(message) => message,
+        };
+
+        c.add_layer(
+            Dialog::around(TextView::new(content).no_wrap().scrollable().scroll_x(true))
+                .title("Patch history")
+                .dismiss_button("Close"),
+        );
+    });
+}
+
+/// Get the status from the appliance manager on a background thread and then
+/// call the function on the UI thread with the status or an error message
+fn request_status<F>(cursive: &mut Cursive, control_socket: &Path, callback: F)
+where
+    F: FnOnce(&mut Cursive, Result<control::Status, String>) + Send + 'static,
+{
+    let control_socket = control_socket.to_path_buf();
+    let cb_sink = cursive.cb_sink().clone();
+
+    std::thread::spawn(move || {
+        let status = match control::send_request(&control_socket, &control::Request::Status) {
+            Ok(control::Response::Status(status)) => Ok(status),
+            Ok(control::Response::Error { message }) => Err(message),
+            Ok(response) => Err(format!("Unexpected response: {response:?}")),
+            Err(error) => Err(format!(
+                "Could not connect to the appliance manager: {error}"
+            )),
+        };
+
+        let _ = cb_sink.send(Box::new(move |c| callback(c, status)));
+    });
+}
+
+/// Shows a dialog window containing the messages received from the manager
+fn show_event_history_dialog(cursive: &mut Cursive) {
+    let mut filter = SelectView::new().popup();
+    filter.add_item("All events", Severity::Info);
+    filter.add_item("Warnings and errors", Severity::Warning);
+    filter.add_item("Errors only", Severity::Error);
+    filter.set_on_submit(|c, minimum: &Severity| {
+        let content = format_event_history(c, *minimum);
+        c.call_on_name(EVENT_HISTORY_TEXT_VIEW, |view: &mut TextView| {
+            view.set_content(content);
+        });
+    });
+
+    let content = format_event_history(cursive, Severity::Info);
+    let text_view = TextView::new(content)
+        .with_name(EVENT_HISTORY_TEXT_VIEW)
+        .scrollable();
+
+    let mut layout = LinearLayout::new(Orientation::Vertical);
+    layout.add_child(
+        LinearLayout::new(Orientation::Horizontal)
+            .child(TextView::new("Show: "))
+            .child(filter),
+    );
+    layout.add_child(DummyView);
+    layout.add_child(text_view);
+
+    cursive.add_layer(
+        Dialog::around(layout)
+            .title("Event history")
+            .dismiss_button("Close"),
+    );
+}
+
+/// Returns the event history, newest first, with events below the given severity removed
+fn format_event_history(cursive: &mut Cursive, minimum: Severity) -> StyledString {
+    let mut content = StyledString::new();
+
+    cursive.with_user_data(|history: &mut MessageHistory| {
+        for entry in history.entries().rev() {
+            if entry.severity < minimum {
+                continue;
+            }
+
+            let style = severity_style(entry.severity);
+            content.append_styled(
+                format!(
+                    "{} {}\n",
+                    entry.time.format("%Y-%m-%d %H:%M:%S"),
+                    entry.kind
+                ),
+                style.combine(Effect::Bold),
+            );
+
+            for line in entry.text.lines() {
+                content.append_styled(format!("    {line}\n"), style);
+            }
+
+            content.append_plain("\n");
+        }
+    });
+
+    if content.is_empty() {
+        content.append_plain("No events.");
+    }
+
+    content
+}
+
+fn format_manager_status(status: &control::Status) -> String {
+    let mut text = format!(
+        "Phase: {}\n\nContainers:\n",
+        status.phase.as_deref().unwrap_or("unknown")
+    );
+
+    for container in &status.containers {
+        text.push_str(&format!(
+            "    {} ({}): {}",
+            container.name,
+            container.role,
+            container.status.as_deref().unwrap_or("does not exist")
+        ));
+
+        if let Some(health) = &container.health {
+            text.push_str(&format!(", health {health}"));
+        }
+
+        if let Some(exit_code) = container.exit_code {
+            text.push_str(&format!(", exit code {exit_code}"));
+        }
+
+        text.push('\n');
+    }
+
+    text.push_str("\nRecent errors:\n");
+
+    if status.last_errors.is_empty() {
+        text.push_str("    (none)\n");
+    }
+
+    for error in &status.last_errors {
+        text.push_str(&format!(
+            "    {} {}: {}\n",
+            error.time, error.phase, error.text
+        ));
+    }
+
+    text
+}
+
+fn format_patch_history(status: &control::Status) -> String {
+    let mut text = match status.patch_version {
+        Some(version) => format!("Applied patch version: {version}\n\n"),
+        None => "Applied patch version: none\n\n".to_string(),
+    };
+
+    if status.patches.is_empty() {
+        text.push_str("No patches have been run.\n");
+    }
+
+    for patch in &status.patches {
+        let version = match patch.version {
+            Some(version) => format!("version {version}"),
+            None => "unversioned".to_string(),
+        };
+
+        text.push_str(&format!(
+            "{} {version}: {}\n    SHA-256 {}\n",
+            patch.time, patch.outcome, patch.sha256
+        ));
+
+        if let Some(error) = &patch.error {
+            text.push_str(&format!("    {error}\n"));
+        }
+    }
+
+    text
+}
+
 /// Shows a dialog window for performing actions (reboot, etc.)
-fn show_action_dialog(action: &str, cursive: &mut Cursive) {
+fn show_action_dialog(action: &str, control_socket: &Path, cursive: &mut Cursive) {
     let title;
     let text;
-    let command;
+    let request;
+    let fallback_command;
 
     match action {
+        "restart_payload" => {
+            title = "Restart container";
+            text = "Restart the warrior container now?\n\nAny unfinished tasks will be lost.";
+            request = control::Request::RestartPayload;
+            fallback_command = None;
+        }
+        "run_updater" => {
+            title = "Update";
+            text = "Check for and install container updates now?\n\nUpdated containers are restarted and any unfinished tasks will be lost.";
+            request = control::Request::RunUpdater;
+            fallback_command = None;
+        }
+        "run_patch" => {
+            title = "Patch";
+            text = "Check for and install system patches now?\n\nThe system may restart to apply the patch.";
+            request = control::Request::RunPatch;
+            fallback_command = None;
+        }
         "reboot" => {
             title = "Restart";
             text = "Restart the system now?\n\nAny unfinished tasks will be lost.";
-            command = "reboot";
+            request = control::Request::Reboot;
+            fallback_command = Some("reboot");
         }
         "poweroff" => {
             title = "Shut down";
             text = "Shut down the system now?\n\nAny unfinished tasks will be lost.";
-            command = "poweroff";
+            request = control::Request::Poweroff;
+            fallback_command = Some("poweroff");
         }
         _ => {
             unimplemented!()
         }
     }
 
+    let control_socket = control_socket.to_path_buf();
+
     cursive.add_layer(
         Dialog::around(TextView::new(text))
             .title(title)
@@ -355,13 +700,111 @@ fn show_action_dialog(action: &str, cursive: &mut Cursive) {
             .button(title, move |c| {
                 c.pop_layer();
 
-                if is_warrior_vm() {
-                    let _ = std::process::Command::new(command).status();
+                if !is_warrior_vm() {
+                    return;
                 }
+
+                send_control_request(c, &control_socket, request.clone(), fallback_command);
             }),
     );
 }
 
+/// Send the request to the appliance manager on a background thread so the
+/// UI does not freeze while the manager is busy
+///
+/// If the manager does not accept the request, the fallback command is run
+/// instead. Otherwise the error is shown.
+fn send_control_request(
+    cursive: &mut Cursive,
+    control_socket: &Path,
+    request: control::Request,
+    fallback_command: Option<&'static str>,
+) {
+    let control_socket = control_socket.to_path_buf();
+    let cb_sink = cursive.cb_sink().clone();
+
+    std::thread::spawn(move || {
+        let mut text = match control::send_request(&control_socket, &request) {
+            Ok(control::Response::Error { message }) => message,
+            Ok(_) => return,
+            Err(error) => format!("Could not connect to the appliance manager: {error}"),
+        };
+
+        if let Some(command) = fallback_command {
+            match std::process::Command::new(command).status() {
+                Ok(_) => return,
+                Err(error) => text.push_str(&format!("\n\nCould not run {command}: {error}")),
+            }
+        }
+
+        let _ = cb_sink.send(Box::new(move |c| show_error_dialog(c, text)));
+    });
+}
+
+/// Shows a dialog window for selecting the release channel of patches and updates
+fn show_channel_dialog(control_socket: &Path, cursive: &mut Cursive) {
+    let socket_path = control_socket.to_path_buf();
+
+    request_status(cursive, control_socket, move |c, status| match status {
+        Ok(status) => add_channel_dialog(c, socket_path, &status),
+        Err(message) => show_error_dialog(c, message),
+    });
+}
+
+/// Adds the release channel dialog window for the manager's status
+fn add_channel_dialog(cursive: &mut Cursive, control_socket: PathBuf, status: &control::Status) {
+    let mut select = SelectView::new();
+
+    for channel in &status.channels {
+        let label = if status.channel.as_ref() == Some(channel) {
+            format!("{channel} (selected)")
+        } else {
+            channel.clone()
+        };
+        select.add_item(label, channel.clone());
+    }
+
+    if let Some(index) = status
+        .channels
+        .iter()
+        .position(|channel| status.channel.as_ref() == Some(channel))
+    {
+        select.set_selection(index);
+    }
+
+    select.set_on_submit(move |c, channel: &String| {
+        c.pop_layer();
+
+        let request = control::Request::SetChannel {
+            channel: channel.clone(),
+        };
+
+        send_control_request(c, &control_socket, request, None);
+    });
+
+    let mut layout = LinearLayout::new(Orientation::Vertical);
+    layout.add_child(TextView::new(
+        "Select the release channel of system patches and updates.\n\nChannels other than stable get new versions first, which may have problems.",
+    ));
+    layout.add_child(DummyView);
+    layout.add_child(select.scrollable());
+
+    cursive.add_layer(
+        Dialog::around(layout)
+            .title("Release channel")
+            .dismiss_button("Cancel"),
+    );
+}
+
+/// Shows a dialog window with an error message
+fn show_error_dialog(cursive: &mut Cursive, text: String) {
+    cursive.add_layer(
+        Dialog::around(TextView::new(text))
+            .title("Error")
+            .dismiss_button("Close"),
+    );
+}
+
 fn is_warrior_vm() -> bool {
     std::fs::exists("/etc/warrior4-env").unwrap_or_default()
 }
@@ -0,0 +1,122 @@
+//! Dialog that measures the network latency and download speed
+
+use std::process::Command;
+
+use cursive::{
+    view::{Nameable, Scrollable},
+    views::{Dialog, TextView},
+    Cursive,
+};
+use serde::Deserialize;
+
+static NETWORK_SPEED_TEXT_VIEW: &str = "network_speed_text_view";
+
+/// Report printed by `warrior4-network-check --measure --format json`
+#[derive(Debug, Deserialize)]
+struct Report {
+    measurement: Measurement,
+}
+
+#[derive(Debug, Deserialize)]
+struct Measurement {
+    url: String,
+    address: Option<String>,
+    dns_ms: Option<u64>,
+    connect_ms: u64,
+    first_byte_ms: u64,
+    total_ms: u64,
+    bytes: u64,
+    truncated: bool,
+    bytes_per_second: u64,
+}
+
+/// Shows a dialog window and runs the measurement in the background
+pub fn show_network_speed_dialog(cursive: &mut Cursive) {
+    cursive.add_layer(
+        Dialog::around(
+            TextView::new("Measuring...")
+                .no_wrap()
+                .with_name(NETWORK_SPEED_TEXT_VIEW)
+                .scrollable()
+                .scroll_x(true),
+        )
+        .title("Network speed")
+        .dismiss_button("Close"),
+    );
+
+    let cb_sink = cursive.cb_sink().clone();
+
+    std::thread::spawn(move || {
+        let content = run_measurement();
+
+        let _ = cb_sink.send(Box::new(move |c| {
+            c.call_on_name(NETWORK_SPEED_TEXT_VIEW, |view: &mut TextView| {
+                view.set_content(content);
+            });
+        }));
+    });
+}
+
+fn run_measurement() -> String {
+    let output = match Command::new("warrior4-network-check")
+        .arg("--measure")
+        .arg("--format")
+        .arg("json")
+        .output()
+    {
+        Ok(output) => output,
+        Err(error) => return format!("Could not run the network check: {error}"),
+    };
+
+    if !output.status.success() {
+        return format!(
+            "Measurement failed:\n\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    match serde_json::from_slice::<Report>(&output.stdout) {
+        Ok(report) => format_measurement(&report.measurement),
+        Err(error) => format!("Could not read the measurement: {error}"),
+    }
+}
+
+fn format_measurement(measurement: &Measurement) -> String {
+    let mut text = String::new();
+
+    text.push_str(&format!("URL: {}\n", measurement.url));
+
+    if let Some(address) = &measurement.address {
+        text.push_str(&format!("Address: {address}\n"));
+    }
+
+    text.push('\n');
+
+    match measurement.dns_ms {
+        Some(dns_ms) => text.push_str(&format!("DNS lookup: {dns_ms} ms\n")),
+        None => text.push_str("DNS lookup: not needed\n"),
+    }
+
+    text.push_str(&format!("TCP connect: {} ms\n", measurement.connect_ms));
+    text.push_str(&format!(
+        "Time to first byte: {} ms\n",
+        measurement.first_byte_ms
+    ));
+    text.push('\n');
+    text.push_str(&format!(
+        "Download speed: {:.1} Mbit/s\n",
+        measurement.bytes_per_second as f64 * 8.0 / 1_000_000.0
+    ));
+    text.push_str(&format!(
+        "Downloaded: {:.1} MB in {:.1} s{}\n",
+        measurement.bytes as f64 / 1_000_000.0,
+        measurement.total_ms as f64 / 1000.0,
+        if measurement.truncated {
+            " (stopped at the limit)"
+        } else {
+            ""
+        }
+    ));
+
+    text
+}
@@ -1,31 +1,52 @@
 //! Editable configuration file loading
 
 use std::{
+    collections::{BTreeMap, HashSet},
     net::SocketAddr,
     path::{Path, PathBuf},
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::files::sha256_hex;
 
 /// The config that gets loaded from the toml config file
 #[derive(Deserialize)]
 pub struct AppConfig {
-    pub log_path: PathBuf,
     pub state_path: PathBuf,
     pub display_ipc_address: SocketAddr,
+    #[serde(default = "default_docker_socket_path")]
+    pub docker_socket_path: PathBuf,
+    #[serde(default = "default_control_socket_path")]
+    pub control_socket_path: PathBuf,
     pub patch_script_url: Option<String>,
+    /// URL of a patch manifest listing files to install, used instead of `patch_script_url`
+    pub patch_manifest_url: Option<String>,
+    /// URL of the minisign signature of the patch file (the patch URL with `.minisig` by default)
+    pub patch_signature_url: Option<String>,
+    /// URL of a release file listing the appliance binaries to update to
+    pub self_update_url: Option<String>,
+    /// Minisign public keys trusted to sign the patch and release files
+    #[serde(default)]
+    pub patch_public_keys: Vec<String>,
+    /// Release channels with their own URLs that can be selected from the display
+    #[serde(default)]
+    pub channels: BTreeMap<String, ChannelConfig>,
+    /// Channel used until another is selected. The URLs above belong to it
+    /// unless it is also listed in `channels`.
+    #[serde(default = "default_channel")]
+    pub default_channel: String,
 
-    // Watchtower container
-    pub watchtower_name: String,
-    pub watchtower_creator: PathBuf,
+    // The containers to create and manage (the built-in containers if none are declared)
+    #[serde(default)]
+    pub containers: Vec<ContainerConfig>,
 
-    // Watchtower container but with --run-once
-    pub watchtower_run_once_name: String,
-    pub watchtower_run_once_creator: PathBuf,
+    // Container names in configs written before containers were declared
+    watchtower_name: Option<String>,
+    watchtower_run_once_name: Option<String>,
+    payload_name: Option<String>,
 
-    // The warrior project management container
-    pub payload_name: String,
-    pub payload_creator: PathBuf,
+    // Hooks for the payload container
     pub payload_pre_start: PathBuf,
     pub payload_post_start: PathBuf,
     pub payload_wait_ready: PathBuf,
@@ -37,14 +58,456 @@ pub struct AppConfig {
     pub reboot_on_payload_unhealthy: bool,
 }
 
+impl AppConfig {
+    /// Returns the container with the payload role
+    pub fn payload_container(&self) -> &ContainerConfig {
+        self.containers_with_role(ContainerRole::Payload)
+            .next()
+            .expect("validated config has a payload container")
+    }
+
+    /// Returns the containers with the given role in declaration order
+    pub fn containers_with_role(
+        &self,
+        role: ContainerRole,
+    ) -> impl Iterator<Item = &ContainerConfig> {
+        self.containers
+            .iter()
+            .filter(move |container| container.role == role)
+    }
+
+    /// Returns the URLs of the release channel
+    pub fn channel(&self, name: &str) -> ChannelConfig {
+        match self.channels.get(name) {
+            Some(channel) => channel.clone(),
+            None => ChannelConfig {
+                patch_script_url: self.patch_script_url.clone(),
+                patch_manifest_url: self.patch_manifest_url.clone(),
+                patch_signature_url: self.patch_signature_url.clone(),
+                self_update_url: self.self_update_url.clone(),
+                rollout_url: None,
+            },
+        }
+    }
+
+    /// Returns the names of the release channels starting with the default channel
+    pub fn channel_names(&self) -> Vec<String> {
+        let mut names = vec![self.default_channel.clone()];
+
+        for name in self.channels.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+
+        names
+    }
+
+    /// Use the built-in containers if the config does not declare any
+    fn add_builtin_containers(&mut self) -> anyhow::Result<()> {
+        if !self.containers.is_empty() {
+            return Ok(());
+        }
+
+        tracing::warn!("no containers declared, using the built-in containers");
+
+        let mut containers = toml::from_str::<BuiltinContainers>(BUILTIN_CONTAINERS)?.containers;
+
+        for container in &mut containers {
+            let name = match container.role {
+                ContainerRole::Service => &self.watchtower_name,
+                ContainerRole::Updater => &self.watchtower_run_once_name,
+                ContainerRole::Payload => &self.payload_name,
+            };
+
+            if let Some(name) = name {
+                container.name.clone_from(name);
+            }
+        }
+
+        self.containers = containers;
+
+        Ok(())
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let mut names = HashSet::new();
+
+        for container in &self.containers {
+            if !names.insert(&container.name) {
+                anyhow::bail!("duplicate container name {}", container.name);
+            }
+        }
+
+        let payload_count = self.containers_with_role(ContainerRole::Payload).count();
+
+        if payload_count != 1 {
+            anyhow::bail!(
+                "exactly one container must have the payload role, found {payload_count}"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A `[channels.<name>]` table with the URLs of a release channel
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChannelConfig {
+    pub patch_script_url: Option<String>,
+    pub patch_manifest_url: Option<String>,
+    pub patch_signature_url: Option<String>,
+    pub self_update_url: Option<String>,
+    /// URL of a JSON object such as `{"percent": 25}` limiting the channel's
+    /// patches and updates to that share of machines
+    pub rollout_url: Option<String>,
+}
+
+impl ChannelConfig {
+    /// Returns the URL of the patch manifest or script
+    pub fn patch_url(&self) -> Option<&str> {
+        self.patch_manifest_url
+            .as_deref()
+            .or(self.patch_script_url.as_deref())
+    }
+
+    /// Returns the URL of the patch file's signature
+    pub fn patch_signature_url(&self) -> Option<String> {
+        self.patch_signature_url
+            .clone()
+            .or_else(|| self.patch_url().map(|url| format!("{url}.minisig")))
+    }
+}
+
+/// Containers used when the config does not declare any, as in the
+/// creator scripts of configs from before containers were declared
+const BUILTIN_CONTAINERS: &str = r#"
+[[containers]]
+name = "watchtower"
+role = "service"
+image = "containrrr/watchtower"
+command = ["--cleanup", "--include-stopped", "--interval", "3600"]
+binds = ["/var/run/docker.sock:/var/run/docker.sock"]
+
+[[containers]]
+name = "watch-once-tower"
+role = "updater"
+image = "containrrr/watchtower"
+command = ["--cleanup", "--include-stopped", "--run-once"]
+binds = ["/var/run/docker.sock:/var/run/docker.sock"]
+auto_remove = true
+
+[[containers]]
+name = "warrior"
+role = "payload"
+image = "atdr.meo.ws/archiveteam/warrior-dockerfile"
+ports = ["8001:8001"]
+binds = [
+    "/root/config.json:/home/warrior/projects/config.json",
+    "/tmp/warrior:/tmp",
+]
+"#;
+
+#[derive(Deserialize)]
+struct BuiltinContainers {
+    containers: Vec<ContainerConfig>,
+}
+
+/// A `[[containers]]` table describing how a Docker container is created
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerConfig {
+    pub name: String,
+    pub role: ContainerRole,
+    pub image: String,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<String>,
+    #[serde(default)]
+    pub binds: Vec<String>,
+    #[serde(default)]
+    pub tmpfs: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub auto_remove: bool,
+}
+
+impl ContainerConfig {
+    /// Returns a hex SHA-256 digest of the declaration used to detect changes
+    /// made after the container was created
+    pub fn spec_hash(&self) -> String {
+        let spec = ContainerSpec {
+            name: &self.name,
+            image: &self.image,
+            command: &self.command,
+            ports: &self.ports,
+            binds: &self.binds,
+            tmpfs: &self.tmpfs,
+            env: &self.env,
+            restart: self.restart,
+            auto_remove: self.auto_remove,
+        };
+        let doc = serde_json::to_vec(&spec).expect("container spec is serializable");
+
+        sha256_hex(&doc)
+    }
+}
+
+/// The fields of a declaration that the created container depends on
+///
+/// Adding a field changes the hash of every container and recreates them,
+/// so a new field should be skipped while it has its default value.
+#[derive(Serialize)]
+struct ContainerSpec<'a> {
+    name: &'a str,
+    image: &'a str,
+    command: &'a [String],
+    ports: &'a [String],
+    binds: &'a [String],
+    tmpfs: &'a [String],
+    env: &'a BTreeMap<String, String>,
+    restart: RestartPolicy,
+    auto_remove: bool,
+}
+
+/// How the manager uses a container
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRole {
+    /// The warrior project management container that is monitored
+    Payload,
+    /// A long running container started alongside the payload
+    Service,
+    /// A container run to completion before the others are started to update them
+    Updater,
+}
+
+/// Docker restart policy of a container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    Always,
+    UnlessStopped,
+    OnFailure,
+}
+
+impl RestartPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestartPolicy::No => "no",
+            RestartPolicy::Always => "always",
+            RestartPolicy::UnlessStopped => "unless-stopped",
+            RestartPolicy::OnFailure => "on-failure",
+        }
+    }
+}
+
+fn default_channel() -> String {
+    "stable".to_string()
+}
+
+fn default_docker_socket_path() -> PathBuf {
+    PathBuf::from("/var/run/docker.sock")
+}
+
+fn default_control_socket_path() -> PathBuf {
+    PathBuf::from(warrior4_appliance_display::control::DEFAULT_SOCKET_PATH)
+}
+
+/// Paths from the config that are needed before the rest of it is loaded
+///
+/// `log_path` is only read here.
+#[derive(Deserialize)]
+pub struct StartupPaths {
+    pub log_path: PathBuf,
+    pub state_path: PathBuf,
+}
+
+/// Deserialize only the log and state paths from the given path
+///
+/// A pending self-update is checked with these before the full config is
+/// loaded, so it is rolled back even if the new binary rejects the config.
+pub fn load_startup_paths(path: &Path) -> anyhow::Result<StartupPaths> {
+    let config_text = std::fs::read_to_string(path)?;
+
+    Ok(toml::from_str::<StartupPaths>(&config_text)?)
+}
+
 /// Deserialize the config from the given path
 pub fn load_config(path: &Path) -> anyhow::Result<AppConfig> {
     tracing::info!("reading configuration");
 
     let config_text = std::fs::read_to_string(path)?;
-    let config = toml::from_str::<AppConfig>(&config_text)?;
+    let config = parse_config(&config_text)?;
 
     tracing::info!("loaded configuration");
 
     Ok(config)
 }
+
+/// Parse the text of a config file and add the built-in containers
+pub fn parse_config(config_text: &str) -> anyhow::Result<AppConfig> {
+    let mut config = toml::from_str::<AppConfig>(config_text)?;
+    config.add_builtin_containers()?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The settings other than the containers
+    const BASE_CONFIG: &str = r#"
+state_path = "/var/lib/warrior4-appliance/state.json"
+display_ipc_address = "127.0.0.1:40100"
+payload_pre_start = "/bin/true"
+payload_post_start = "/bin/true"
+payload_wait_ready = "/bin/true"
+payload_reboot_check = "/bin/true"
+payload_poweroff_check = "/bin/true"
+payload_ready_message = "ready"
+reboot_on_payload_exit_error = false
+reboot_on_payload_unhealthy = false
+"#;
+
+    fn parse(extra: &str) -> anyhow::Result<AppConfig> {
+        parse_config(&format!("{BASE_CONFIG}{extra}"))
+    }
+
+    /// Returns the error message of a config that is rejected
+    fn parse_error(extra: &str) -> String {
+        match parse(extra) {
+            Ok(_) => panic!("config was accepted"),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    fn names(config: &AppConfig) -> Vec<&str> {
+        config
+            .containers
+            .iter()
+            .map(|container| container.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_containers() {
+        let config = parse("").unwrap();
+
+        assert_eq!(
+            names(&config),
+            ["watchtower", "watch-once-tower", "warrior"]
+        );
+        assert_eq!(config.payload_container().name, "warrior");
+        assert_eq!(
+            config
+                .containers_with_role(ContainerRole::Updater)
+                .map(|container| container.auto_remove)
+                .collect::<Vec<_>>(),
+            [true]
+        );
+    }
+
+    #[test]
+    fn test_legacy_container_names() {
+        let config = parse(
+            r#"
+watchtower_name = "old-watchtower"
+watchtower_run_once_name = "old-watch-once"
+payload_name = "old-warrior"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            names(&config),
+            ["old-watchtower", "old-watch-once", "old-warrior"]
+        );
+    }
+
+    #[test]
+    fn test_declared_containers_replace_builtin() {
+        let config = parse(
+            r#"
+payload_name = "ignored"
+
+[[containers]]
+name = "payload"
+role = "payload"
+image = "example.org/payload"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(names(&config), ["payload"]);
+    }
+
+    #[test]
+    fn test_spec_hash() {
+        let config = parse("").unwrap();
+        let payload = config.payload_container();
+        let mut changed = payload.clone();
+        changed.env.insert("KEY".to_string(), "value".to_string());
+        let mut other_role = payload.clone();
+        other_role.role = ContainerRole::Service;
+
+        assert_eq!(payload.spec_hash(), payload.clone().spec_hash());
+        assert_ne!(payload.spec_hash(), changed.spec_hash());
+        // The role only changes how the manager uses the container
+        assert_eq!(payload.spec_hash(), other_role.spec_hash());
+    }
+
+    #[test]
+    fn test_duplicate_container_names() {
+        let error = parse_error(
+            r#"
+[[containers]]
+name = "payload"
+role = "payload"
+image = "example.org/payload"
+
+[[containers]]
+name = "payload"
+role = "service"
+image = "example.org/service"
+"#,
+        );
+
+        assert!(error.contains("duplicate container name"));
+    }
+
+    #[test]
+    fn test_payload_count() {
+        let none = parse_error(
+            r#"
+[[containers]]
+name = "service"
+role = "service"
+image = "example.org/service"
+"#,
+        );
+        let two = parse_error(
+            r#"
+[[containers]]
+name = "payload"
+role = "payload"
+image = "example.org/payload"
+
+[[containers]]
+name = "other-payload"
+role = "payload"
+image = "example.org/payload"
+"#,
+        );
+
+        assert!(none.contains("found 0"));
+        assert!(two.contains("found 2"));
+    }
+}
@@ -1,84 +1,881 @@
-//! Helper functions to the Docker commands
-
-use std::process::{Command, Output};
-
-pub fn get_container_status<S: AsRef<str>>(name: S) -> String {
-    let output = {
-        let result = Command::new("docker")
-            .arg("inspect")
-            .arg("--type=container")
-            .arg("--format")
-            .arg("{{.State.Status}}")
-            .arg(name.as_ref())
-            .output();
-
-        match result {
-            Ok(output) => output,
-            Err(error) => {
-                tracing::trace!(?error, "get container status");
-                return String::new();
+//! Docker Engine API client that talks HTTP over the Docker Unix socket
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::ContainerConfig;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const PULL_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+const RUN_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Lifecycle status of a container as reported by `State.Status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    Created,
+    Restarting,
+    Running,
+    Removing,
+    Paused,
+    Exited,
+    Dead,
+}
+
+/// Health check status as reported by `State.Health.Status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    None,
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerHealth {
+    pub status: HealthStatus,
+}
+
+/// The `State` object of a container inspect response
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ContainerState {
+    pub status: ContainerStatus,
+    pub exit_code: i64,
+    #[serde(default)]
+    pub health: Option<ContainerHealth>,
+}
+
+impl ContainerState {
+    /// Returns the health check status or `None` if the container has no health check
+    pub fn health_status(&self) -> Option<HealthStatus> {
+        self.health.as_ref().map(|health| health.status)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InspectResponse {
+    state: ContainerState,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct WaitResponse {
+    status_code: i64,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// A line of the image pull progress stream
+#[derive(Deserialize)]
+struct PullProgress {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    progress: String,
+    error: Option<String>,
+}
+
+/// Blocking Docker Engine API client
+pub struct DockerClient {
+    socket_path: PathBuf,
+}
+
+impl DockerClient {
+    pub fn new<P: Into<PathBuf>>(socket_path: P) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+        }
+    }
+
+    /// Check whether the daemon is up and responding
+    pub fn ping(&self) -> anyhow::Result<()> {
+        let response = self.request("GET", "/_ping", None)?;
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Returns the container state or `None` if the container does not exist
+    pub fn inspect_container(&self, name: &str) -> anyhow::Result<Option<ContainerState>> {
+        let response = self.request("GET", &format!("/containers/{name}/json"), None)?;
+
+        if response.status == 404 {
+            return Ok(None);
+        }
+
+        let response = response.error_for_status()?;
+        let doc = serde_json::from_slice::<InspectResponse>(&response.body)
+            .context("invalid container inspect response")?;
+
+        Ok(Some(doc.state))
+    }
+
+    /// Download the image, calling the callback with each progress message
+    pub fn pull_image<C>(&self, image: &str, mut progress_callback: C) -> anyhow::Result<()>
+    where
+        C: FnMut(&str),
+    {
+        tracing::info!(image, "pull image");
+
+        let (name, tag) = split_image_reference(image);
+        let mut reader = self.begin_request(
+            "POST",
+            &format!(
+                "/images/create?fromImage={}&tag={}",
+                percent_encode(name),
+                percent_encode(tag)
+            ),
+            None,
+        )?;
+        reader.get_ref().set_read_timeout(Some(PULL_TIMEOUT))?;
+        let head = read_response_head(&mut reader)?;
+
+        if !(200..300).contains(&head.status) {
+            let body = head.read_body(&mut reader)?;
+            return Err(api_error(head.status, &body));
+        }
+
+        // Errors during the pull are reported in the stream with a success status code
+        for line in BufReader::new(head.body_reader(reader)).lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let doc = serde_json::from_str::<PullProgress>(&line)
+                .with_context(|| format!("invalid image pull progress {line:?}"))?;
+
+            if let Some(error) = doc.error {
+                anyhow::bail!("pulling image {image} failed: {error}");
             }
+
+            let text = format!("{} {} {}", doc.id, doc.status, doc.progress);
+            progress_callback(text.trim());
         }
+
+        Ok(())
+    }
+
+    /// Create the container from its declaration. The image must already exist.
+    pub fn create_container(&self, config: &ContainerConfig) -> anyhow::Result<()> {
+        tracing::info!(name = config.name, image = config.image, "create container");
+
+        let mut exposed_ports = serde_json::Map::new();
+        let mut port_bindings = serde_json::Map::new();
+
+        for spec in &config.ports {
+            let (container_port, host_ip, host_port) = parse_port_mapping(spec)?;
+
+            exposed_ports.insert(container_port.clone(), json!({}));
+            port_bindings.insert(
+                container_port,
+                json!([{"HostIp": host_ip, "HostPort": host_port}]),
+            );
+        }
+
+        let tmpfs = config
+            .tmpfs
+            .iter()
+            .map(|spec| match spec.split_once(':') {
+                Some((path, options)) => (path.to_string(), json!(options)),
+                None => (spec.to_string(), json!("")),
+            })
+            .collect::<serde_json::Map<_, _>>();
+
+        let env = config
+            .env
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+
+        let mut body = json!({
+            "Image": config.image,
+            "Env": env,
+            "ExposedPorts": exposed_ports,
+            "HostConfig": {
+                "Binds": config.binds,
+                "PortBindings": port_bindings,
+                "Tmpfs": tmpfs,
+                "RestartPolicy": {"Name": config.restart.as_str()},
+                "AutoRemove": config.auto_remove,
+            },
+        });
+
+        if !config.command.is_empty() {
+            body["Cmd"] = json!(config.command);
+        }
+
+        let response = self.request(
+            "POST",
+            &format!("/containers/create?name={}", percent_encode(&config.name)),
+            Some(&serde_json::to_vec(&body)?),
+        )?;
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Stop and delete the container. Anonymous volumes are not deleted, but
+    /// they are not attached to a container created again with the same name.
+    pub fn remove_container(&self, name: &str) -> anyhow::Result<()> {
+        tracing::info!(name, "remove container");
+
+        let response = self.request("DELETE", &format!("/containers/{name}?force=true"), None)?;
+
+        if response.status != 404 {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Start the container. Starting an already running container is not an error.
+    pub fn start_container(&self, name: &str) -> anyhow::Result<()> {
+        tracing::info!(name, "start container");
+
+        let response = self.request("POST", &format!("/containers/{name}/start"), None)?;
+
+        if response.status != 304 {
+            response.error_for_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Restart the container
+    pub fn restart_container(&self, name: &str) -> anyhow::Result<()> {
+        tracing::info!(name, "restart container");
+
+        let response = self.request("POST", &format!("/containers/{name}/restart"), None)?;
+        response.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Start the container and block until it exits, returning its exit code
+    ///
+    /// An error is returned if the container runs longer than an hour.
+    pub fn run_container_foreground(&self, name: &str) -> anyhow::Result<i64> {
+        // Register the wait before starting so a container that exits
+        // (and is auto removed) quickly is not missed.
+        let mut wait = self.begin_request(
+            "POST",
+            &format!("/containers/{name}/wait?condition=next-exit"),
+            None,
+        )?;
+        wait.get_ref().set_read_timeout(Some(RUN_TIMEOUT))?;
+        let head = read_response_head(&mut wait)?;
+
+        if !(200..300).contains(&head.status) {
+            let body = head.read_body(&mut wait)?;
+            return Err(api_error(head.status, &body));
+        }
+
+        self.start_container(name)?;
+
+        let body = head.read_body(&mut wait)?;
+        let doc = serde_json::from_slice::<WaitResponse>(&body)
+            .context("invalid container wait response")?;
+
+        tracing::info!(name, exit_code = doc.status_code, "container exited");
+
+        Ok(doc.status_code)
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> anyhow::Result<Response> {
+        let mut reader = self.begin_request(method, path, body)?;
+        let head = read_response_head(&mut reader)?;
+        let body = head.read_body(reader)?;
+
+        tracing::trace!(method, path, status = head.status, "docker api response");
+
+        Ok(Response {
+            status: head.status,
+            body,
+        })
+    }
+
+    fn begin_request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&[u8]>,
+    ) -> anyhow::Result<BufReader<UnixStream>> {
+        let mut stream = UnixStream::connect(&self.socket_path)
+            .with_context(|| format!("connecting to {:?} failed", self.socket_path))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let body = body.unwrap_or_default();
+        let mut buf = Vec::new();
+        write!(
+            buf,
+            "{method} {path} HTTP/1.1\r\n\
+            Host: docker\r\n\
+            Connection: close\r\n\
+            Content-Type: application/json\r\n\
+            Content-Length: {}\r\n\r\n",
+            body.len()
+        )?;
+        buf.extend_from_slice(body);
+        stream.write_all(&buf)?;
+
+        Ok(BufReader::new(stream))
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error_for_status(self) -> anyhow::Result<Self> {
+        if (200..300).contains(&self.status) {
+            Ok(self)
+        } else {
+            Err(api_error(self.status, &self.body))
+        }
+    }
+}
+
+fn api_error(status: u16, body: &[u8]) -> anyhow::Error {
+    let message = match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(doc) => doc.message,
+        Err(_) => String::from_utf8_lossy(body).trim().to_string(),
     };
 
-    String::from_utf8_lossy(&output.stdout).trim().to_string()
+    anyhow::anyhow!("docker API error {status}: {message}")
+}
+
+/// Split an image reference into the name and the tag or digest
+fn split_image_reference(image: &str) -> (&str, &str) {
+    if let Some((name, digest)) = image.split_once('@') {
+        return (name, digest);
+    }
+
+    match image.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, tag),
+        _ => (image, "latest"),
+    }
+}
+
+/// Parse a `[host_ip:]host_port:container_port[/protocol]` port mapping
+fn parse_port_mapping(spec: &str) -> anyhow::Result<(String, String, String)> {
+    let (mapping, protocol) = spec.rsplit_once('/').unwrap_or((spec, "tcp"));
+    let mut parts = mapping.rsplitn(3, ':');
+
+    let container_port = parts.next().unwrap_or_default();
+    let host_port = parts.next().unwrap_or_default();
+    let host_ip = parts.next().unwrap_or_default();
+
+    if container_port.parse::<u16>().is_err() {
+        anyhow::bail!("invalid port mapping {spec:?}");
+    }
+
+    Ok((
+        format!("{container_port}/{protocol}"),
+        host_ip.to_string(),
+        host_port.to_string(),
+    ))
 }
 
-pub fn get_container_exit_code<S: AsRef<str>>(name: S) -> Option<u32> {
-    let result = Command::new("docker")
-        .arg("inspect")
-        .arg("--type=container")
-        .arg("--format")
-        .arg("{{.State.ExitCode}}")
-        .arg(name.as_ref())
-        .output();
+fn percent_encode(text: &str) -> String {
+    let mut output = String::new();
 
-    match result {
-        Ok(output) => String::from_utf8_lossy(&output.stdout).trim().parse().ok(),
-        Err(error) => {
-            tracing::trace!(?error, "get container exit code");
-            None
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{byte:02X}"));
         }
     }
+
+    output
 }
 
-pub fn get_container_health<S: AsRef<str>>(name: S) -> Option<String> {
-    let result = Command::new("docker")
-        .arg("inspect")
-        .arg("--type=container")
-        .arg("--format")
-        .arg("{{.State.Health.Status}}")
-        .arg(name.as_ref())
-        .output();
+struct ResponseHead {
+    status: u16,
+    body_kind: BodyKind,
+}
 
-    match result {
-        Ok(output) => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()),
-        Err(error) => {
-            tracing::trace!(?error, "get container health status");
-            None
+impl ResponseHead {
+    fn body_reader<R: BufRead>(&self, reader: R) -> BodyReader<R> {
+        BodyReader {
+            inner: reader,
+            kind: self.body_kind,
+            remaining: match self.body_kind {
+                BodyKind::Length(length) => length,
+                _ => 0,
+            },
+            done: false,
         }
     }
+
+    fn read_body<R: BufRead>(&self, reader: R) -> anyhow::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.body_reader(reader).read_to_end(&mut body)?;
+
+        Ok(body)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BodyKind {
+    Chunked,
+    Length(u64),
+    UntilClose,
+}
+
+/// Reads a response body according to its transfer encoding
+struct BodyReader<R> {
+    inner: R,
+    kind: BodyKind,
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> BodyReader<R> {
+    fn read_chunk_size(&mut self) -> std::io::Result<u64> {
+        let mut line = String::new();
+        self.inner.read_line(&mut line)?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+
+        u64::from_str_radix(size, 16).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid chunk size {line:?}"),
+            )
+        })
+    }
 }
 
-pub fn run_container_foreground<S: AsRef<str>>(name: S) -> anyhow::Result<(Output, Output)> {
-    let mut start_command = Command::new("docker");
-    start_command.arg("start").arg(name.as_ref());
+impl<R: BufRead> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if let BodyKind::UntilClose = self.kind {
+            return self.inner.read(buf);
+        }
+
+        if self.remaining == 0 {
+            match self.kind {
+                BodyKind::Chunked => {
+                    self.remaining = self.read_chunk_size()?;
 
-    let start_output = crate::logging::log_command_output(&mut start_command)?;
+                    if self.remaining == 0 {
+                        self.done = true;
+                        return Ok(0);
+                    }
+                }
+                _ => {
+                    self.done = true;
+                    return Ok(0);
+                }
+            }
+        }
 
-    let mut wait_command = Command::new("docker");
-    wait_command.arg("wait").arg(name.as_ref());
+        let max = buf.len().min(self.remaining as usize);
+        let amount = self.inner.read(&mut buf[..max])?;
+
+        if amount == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
 
-    let wait_output = crate::logging::log_command_output(&mut wait_command)?;
+        self.remaining -= amount as u64;
 
-    Ok((start_output, wait_output))
+        if self.remaining == 0 && matches!(self.kind, BodyKind::Chunked) {
+            // Discard the CRLF that ends the chunk
+            let mut line = String::new();
+            self.inner.read_line(&mut line)?;
+        }
+
+        Ok(amount)
+    }
 }
 
-pub fn start_container<S: AsRef<str>>(name: S) -> anyhow::Result<Output> {
-    let mut command = Command::new("docker");
-    command.arg("start").arg(name.as_ref());
+fn read_response_head<R: BufRead>(reader: &mut R) -> anyhow::Result<ResponseHead> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
 
-    let output = crate::logging::log_command_output(&mut command)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .with_context(|| format!("invalid HTTP status line {line:?}"))?;
+
+    let mut body_kind = BodyKind::UntilClose;
+
+    loop {
+        line.clear();
+        let amount = reader.read_line(&mut line)?;
+
+        if amount == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+
+            if name.eq_ignore_ascii_case("transfer-encoding")
+                && value.eq_ignore_ascii_case("chunked")
+            {
+                body_kind = BodyKind::Chunked;
+            } else if name.eq_ignore_ascii_case("content-length")
+                && !matches!(body_kind, BodyKind::Chunked)
+            {
+                if let Ok(length) = value.parse() {
+                    body_kind = BodyKind::Length(length);
+                }
+            }
+        }
+    }
+
+    Ok(ResponseHead { status, body_kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::net::UnixListener,
+        path::{Path, PathBuf},
+        thread::JoinHandle,
+    };
+
+    use super::*;
+
+    /// Start a fake Docker daemon on a Unix socket that answers each
+    /// connection with the next response and returns the requests it received
+    fn spawn_fake_daemon(name: &str, responses: Vec<String>) -> (PathBuf, JoinHandle<Vec<String>>) {
+        let path = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-{name}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                requests.push(read_request(&mut reader));
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+
+            requests
+        });
+
+        (path, handle)
+    }
+
+    /// Read the request line and headers, then the body by its length
+    fn read_request<R: BufRead>(reader: &mut R) -> String {
+        let mut request = String::new();
+        let mut length = 0;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+
+            if line.trim().is_empty() {
+                break;
+            }
 
-    Ok(output)
+            request.push_str(&line);
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+
+        request
+    }
+
+    /// Returns a response with a body of known length
+    fn response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Returns a response with a body sent in the given chunks
+    fn chunked(chunks: &[&str]) -> String {
+        let mut response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_string();
+
+        for chunk in chunks {
+            response.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+        }
+
+        response.push_str("0\r\n\r\n");
+        response
+    }
+
+    fn remove_socket(path: &Path) {
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_response_head() {
+        let mut reader = "HTTP/1.1 201 Created\r\nContent-Length: 5\r\n\r\nhello".as_bytes();
+        let head = read_response_head(&mut reader).unwrap();
+
+        assert_eq!(head.status, 201);
+        assert!(matches!(head.body_kind, BodyKind::Length(5)));
+        assert_eq!(head.read_body(reader).unwrap(), b"hello");
+
+        let mut reader =
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: Chunked\r\nContent-Length: 3\r\n\r\n".as_bytes();
+        let head = read_response_head(&mut reader).unwrap();
+
+        assert!(matches!(head.body_kind, BodyKind::Chunked));
+
+        let mut reader = "HTTP/1.0 204 No Content\r\n\r\n".as_bytes();
+        let head = read_response_head(&mut reader).unwrap();
+
+        assert_eq!(head.status, 204);
+        assert!(matches!(head.body_kind, BodyKind::UntilClose));
+
+        let mut reader = "garbage\r\n\r\n".as_bytes();
+        assert!(read_response_head(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let body = "4\r\nWiki\r\n7;name=value\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\n\r\nextra";
+        let mut reader = BodyReader {
+            inner: body.as_bytes(),
+            kind: BodyKind::Chunked,
+            remaining: 0,
+            done: false,
+        };
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+
+        assert_eq!(text, "Wikipedia in \r\nchunks.");
+
+        let mut reader = BodyReader {
+            inner: "zz\r\n".as_bytes(),
+            kind: BodyKind::Chunked,
+            remaining: 0,
+            done: false,
+        };
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+
+        let mut reader = BodyReader {
+            inner: "5\r\nabc".as_bytes(),
+            kind: BodyKind::Chunked,
+            remaining: 0,
+            done: false,
+        };
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_parse_port_mapping() {
+        assert_eq!(
+            parse_port_mapping("8001:8001").unwrap(),
+            ("8001/tcp".to_string(), String::new(), "8001".to_string())
+        );
+        assert_eq!(
+            parse_port_mapping("127.0.0.1:8080:80/udp").unwrap(),
+            (
+                "80/udp".to_string(),
+                "127.0.0.1".to_string(),
+                "8080".to_string()
+            )
+        );
+        assert_eq!(
+            parse_port_mapping("80").unwrap(),
+            ("80/tcp".to_string(), String::new(), String::new())
+        );
+        assert!(parse_port_mapping("8001:http").is_err());
+    }
+
+    #[test]
+    fn test_split_image_reference() {
+        assert_eq!(
+            split_image_reference("atdr.meo.ws/archiveteam/warrior-dockerfile:latest"),
+            ("atdr.meo.ws/archiveteam/warrior-dockerfile", "latest")
+        );
+        assert_eq!(
+            split_image_reference("registry:5000/warrior"),
+            ("registry:5000/warrior", "latest")
+        );
+        assert_eq!(split_image_reference("alpine"), ("alpine", "latest"));
+        assert_eq!(
+            split_image_reference("alpine@sha256:abcd"),
+            ("alpine", "sha256:abcd")
+        );
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("warrior-4_a.b~c"), "warrior-4_a.b~c");
+        assert_eq!(percent_encode("a/b:c d"), "a%2Fb%3Ac%20d");
+        assert_eq!(percent_encode("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn test_inspect_container() {
+        let (path, server) = spawn_fake_daemon(
+            "inspect",
+            vec![
+                chunked(&[
+                    r#"{"State": {"Status": "run"#,
+                    r#"ning", "ExitCode": 0, "Health": {"Status": "healthy"}}}"#,
+                ]),
+                response("404 Not Found", r#"{"message": "No such container: x"}"#),
+            ],
+        );
+        let client = DockerClient::new(&path);
+
+        let state = client.inspect_container("warrior").unwrap().unwrap();
+        assert_eq!(state.status, ContainerStatus::Running);
+        assert_eq!(state.health_status(), Some(HealthStatus::Healthy));
+        assert!(client.inspect_container("x").unwrap().is_none());
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /containers/warrior/json HTTP/1.1\r\n"));
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn test_api_error() {
+        let (path, server) = spawn_fake_daemon(
+            "error",
+            vec![response(
+                "500 Internal Server Error",
+                r#"{"message": "daemon broken"}"#,
+            )],
+        );
+        let client = DockerClient::new(&path);
+
+        let error = client.restart_container("warrior").unwrap_err();
+        assert_eq!(error.to_string(), "docker API error 500: daemon broken");
+
+        server.join().unwrap();
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn test_create_container() {
+        let (path, server) = spawn_fake_daemon("create", vec![response("201 Created", "{}")]);
+        let client = DockerClient::new(&path);
+        let config = toml::from_str::<ContainerConfig>(
+            r#"
+            name = "warrior"
+            role = "payload"
+            image = "warrior:latest"
+            ports = ["127.0.0.1:8001:8001"]
+            tmpfs = ["/tmp:size=100m"]
+            env = { DOWNLOADER = "me" }
+            restart = "unless-stopped"
+            "#,
+        )
+        .unwrap();
+
+        client.create_container(&config).unwrap();
+
+        let requests = server.join().unwrap();
+        let (head, body) = requests[0].split_once('{').unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&format!("{{{body}")).unwrap();
+
+        assert!(head.starts_with("POST /containers/create?name=warrior HTTP/1.1\r\n"));
+        assert_eq!(body["Image"], "warrior:latest");
+        assert_eq!(body["Env"], json!(["DOWNLOADER=me"]));
+        assert_eq!(
+            body["HostConfig"]["PortBindings"]["8001/tcp"],
+            json!([{"HostIp": "127.0.0.1", "HostPort": "8001"}])
+        );
+        assert_eq!(body["HostConfig"]["Tmpfs"], json!({"/tmp": "size=100m"}));
+        assert_eq!(
+            body["HostConfig"]["RestartPolicy"]["Name"],
+            "unless-stopped"
+        );
+        assert!(body.get("Cmd").is_none());
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn test_pull_image() {
+        let (path, server) = spawn_fake_daemon(
+            "pull",
+            vec![
+                chunked(&[
+                    "{\"status\": \"Pulling from warrior\", \"id\": \"a\"}\n",
+                    "{\"status\": \"Download complete",
+                    "\"}\n",
+                ]),
+                chunked(&[r#"{"error": "manifest unknown"}"#]),
+            ],
+        );
+        let client = DockerClient::new(&path);
+        let mut messages = Vec::new();
+
+        client
+            .pull_image("example.org/warrior:v1", |text| {
+                messages.push(text.to_string())
+            })
+            .unwrap();
+        assert_eq!(messages, ["a Pulling from warrior", "Download complete"]);
+
+        let error = client.pull_image("warrior", |_| {}).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "pulling image warrior failed: manifest unknown"
+        );
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with(
+            "POST /images/create?fromImage=example.org%2Fwarrior&tag=v1 HTTP/1.1\r\n"
+        ));
+        remove_socket(&path);
+    }
+
+    #[test]
+    fn test_run_container_foreground() {
+        let (path, server) = spawn_fake_daemon(
+            "run",
+            vec![
+                response("200 OK", r#"{"StatusCode": 3}"#),
+                response("204 No Content", ""),
+            ],
+        );
+        let client = DockerClient::new(&path);
+
+        assert_eq!(client.run_container_foreground("updater").unwrap(), 3);
+
+        let requests = server.join().unwrap();
+        assert!(requests[0]
+            .starts_with("POST /containers/updater/wait?condition=next-exit HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("POST /containers/updater/start HTTP/1.1\r\n"));
+        remove_socket(&path);
+    }
 }
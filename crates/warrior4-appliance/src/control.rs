@@ -0,0 +1,269 @@
+//! Control socket that lets other programs query and command the manager
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use warrior4_appliance_display::control::{
+    ContainerInfo, ErrorInfo, PatchInfo, Request, Response, Status,
+};
+
+use crate::{
+    config::{AppConfig, ContainerConfig},
+    container::DockerClient,
+    patch::{PatchOutcome, PatchRecord},
+    phase::PhaseOutcome,
+    state::State,
+};
+
+const COMMAND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_LAST_ERRORS: usize = 10;
+
+/// A command forwarded from a control client to the manager
+pub struct ControlCommand {
+    pub request: Request,
+    /// Channel for telling the client whether the command was accepted
+    pub reply: Sender<Response>,
+}
+
+/// Data the control socket threads need to answer requests
+pub struct ControlServer {
+    socket_path: PathBuf,
+    docker: DockerClient,
+    containers: Vec<ContainerConfig>,
+    channels: Vec<String>,
+    default_channel: String,
+    state: Arc<Mutex<State>>,
+    commands: Sender<ControlCommand>,
+    /// Whether the manager is waiting for commands
+    accepting: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    pub fn new(
+        config: &AppConfig,
+        state: Arc<Mutex<State>>,
+        commands: Sender<ControlCommand>,
+        accepting: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            socket_path: config.control_socket_path.clone(),
+            docker: DockerClient::new(&config.docker_socket_path),
+            containers: config.containers.clone(),
+            channels: config.channel_names(),
+            default_channel: config.default_channel.clone(),
+            state,
+            commands,
+            accepting,
+        }
+    }
+
+    /// Bind the socket and accept clients on a background thread
+    pub fn spawn(self) -> anyhow::Result<()> {
+        if self.socket_path.exists() {
+            std::fs::remove_file(&self.socket_path)?;
+        }
+
+        let listener = UnixListener::bind(&self.socket_path)?;
+        std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o600))?;
+
+        tracing::info!(path = ?self.socket_path, "control socket listening");
+
+        let server = Arc::new(self);
+
+        std::thread::spawn(move || loop {
+            match listener.accept() {
+                Ok((stream, _addr)) => {
+                    let server = server.clone();
+                    std::thread::spawn(move || {
+                        if let Err(error) = server.handle_client(stream) {
+                            tracing::debug!(?error, "control client error");
+                        }
+                    });
+                }
+                Err(error) => {
+                    tracing::error!(?error, "control socket accept");
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn handle_client(&self, stream: UnixStream) -> anyhow::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut buf = String::new();
+
+        loop {
+            buf.clear();
+            let amount = reader.read_line(&mut buf)?;
+
+            if amount == 0 {
+                break;
+            }
+
+            let response = match serde_json::from_str::<Request>(&buf) {
+                Ok(request) => {
+                    tracing::info!(?request, "control request");
+                    self.handle_request(request)
+                }
+                Err(error) => Response::Error {
+                    message: format!("invalid request: {error}"),
+                },
+            };
+
+            let mut doc = serde_json::to_vec(&response)?;
+            doc.push(b'\n');
+            writer.write_all(&doc)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        if let Request::Status = request {
+            return Response::Status(self.status());
+        }
+
+        if !self.accepting.load(Ordering::SeqCst) {
+            return self.busy_response();
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let command = ControlCommand {
+            request,
+            reply: sender,
+        };
+
+        if self.commands.send(command).is_err() {
+            return Response::Error {
+                message: "The manager is not running".to_string(),
+            };
+        }
+
+        match receiver.recv_timeout(COMMAND_ACCEPT_TIMEOUT) {
+            Ok(response) => response,
+            Err(_) => self.busy_response(),
+        }
+    }
+
+    fn busy_response(&self) -> Response {
+        Response::Error {
+            message: format!(
+                "The manager is busy ({}). Try again later.",
+                self.phase_name().unwrap_or_default()
+            ),
+        }
+    }
+
+    fn phase_name(&self) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state.phase.map(|phase| phase.description().to_string())
+    }
+
+    fn status(&self) -> Status {
+        let state = self.state.lock().unwrap().clone();
+
+        let last_errors = state
+            .phase_history
+            .iter()
+            .rev()
+            .filter_map(|record| match &record.outcome {
+                PhaseOutcome::Failed { error } => Some(ErrorInfo {
+                    time: record.finished.to_rfc3339(),
+                    phase: record.phase.description().to_string(),
+                    text: error.clone(),
+                }),
+                _ => None,
+            })
+            .take(MAX_LAST_ERRORS)
+            .collect();
+
+        let containers = self
+            .containers
+            .iter()
+            .map(|container| {
+                let mut info = ContainerInfo {
+                    name: container.name.clone(),
+                    role: to_string_value(&container.role),
+                    status: None,
+                    health: None,
+                    exit_code: None,
+                };
+
+                match self.docker.inspect_container(&container.name) {
+                    Ok(Some(container_state)) => {
+                        info.status = Some(to_string_value(&container_state.status));
+                        info.health = container_state.health_status().map(|h| to_string_value(&h));
+                        info.exit_code = Some(container_state.exit_code);
+                    }
+                    Ok(None) => {}
+                    Err(error) => {
+                        info.status = Some(format!("error: {error:#}"));
+                    }
+                }
+
+                info
+            })
+            .collect();
+
+        Status {
+            phase: state.phase.map(|phase| to_string_value(&phase)),
+            containers,
+            last_errors,
+            patch_version: state.patch_version,
+            patches: state.patch_history.iter().rev().map(patch_info).collect(),
+            channel: Some(
+                state
+                    .channel
+                    .clone()
+                    .filter(|channel| self.channels.contains(channel))
+                    .unwrap_or_else(|| self.default_channel.clone()),
+            ),
+            channels: self.channels.clone(),
+            state: serde_json::to_value(&state).unwrap_or_default(),
+        }
+    }
+}
+
+fn patch_info(record: &PatchRecord) -> PatchInfo {
+    let error = match &record.outcome {
+        PatchOutcome::Failed { error } | PatchOutcome::Refused { error } => Some(error.clone()),
+        PatchOutcome::Running | PatchOutcome::Applied => None,
+    };
+    let outcome = match &record.outcome {
+        PatchOutcome::Running => "running",
+        PatchOutcome::Applied => "applied",
+        PatchOutcome::Failed { .. } => "failed",
+        PatchOutcome::Refused { .. } => "refused",
+    };
+
+    PatchInfo {
+        time: record.started.to_rfc3339(),
+        version: record.version,
+        sha256: record.sha256.clone(),
+        outcome: outcome.to_string(),
+        error,
+    }
+}
+
+/// Returns the serde name of a unit enum variant
+fn to_string_value<T: serde::Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(text)) => text,
+        _ => String::new(),
+    }
+}
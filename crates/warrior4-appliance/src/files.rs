@@ -0,0 +1,49 @@
+//! Hashing, downloading, and writing files for patches and self-updates
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+/// Returns the hex SHA-256 hash of the data
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns whether the text is a hex SHA-256 hash
+pub fn is_sha256_hex(text: &str) -> bool {
+    text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Download the URL and check that the body has the hex SHA-256 hash
+pub fn download_verified(url: &str, sha256: &str) -> anyhow::Result<Vec<u8>> {
+    let data = crate::net::download(url).with_context(|| format!("download {url} failed"))?;
+    let hash = sha256_hex(&data);
+
+    if !hash.eq_ignore_ascii_case(sha256) {
+        anyhow::bail!("hash of {url} is {hash}, expected {sha256}");
+    }
+
+    Ok(data)
+}
+
+/// Write the file and flush it to disk
+pub fn write_synced(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Returns the path with an extra extension such as `name.warrior4-new`
+pub fn suffixed_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+
+    path.with_file_name(name)
+}
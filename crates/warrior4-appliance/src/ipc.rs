@@ -1,19 +1,95 @@
 //! IPC to talk to the warrior4-appliance-display service
+//!
+//! Messages are queued and sent by a background thread over a persistent
+//! connection that is reopened when the display restarts. The display asks
+//! for a snapshot of the current screen contents whenever it connects.
 
 use std::{
-    net::{SocketAddr, TcpStream},
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
     time::Duration,
 };
 
-use warrior4_appliance_display::IPCRequest;
+use warrior4_appliance_display::{IPCMessage, IPCRequest, Severity, PROTOCOL_VERSION};
+
+const MAX_QUEUE_LENGTH: usize = 32;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
 
 pub struct DisplayIPC {
-    address: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    outbox: Mutex<Outbox>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct Outbox {
+    queue: VecDeque<IPCRequest>,
+    screen: ScreenState,
+}
+
+/// What the display should currently be showing
+#[derive(Default)]
+struct ScreenState {
+    message: Option<String>,
+    severity: Severity,
+    progress: Option<u8>,
+    command_output: String,
+}
+
+impl ScreenState {
+    fn update(&mut self, request: &IPCRequest) {
+        match request {
+            IPCRequest::CommandOutput { text } => {
+                self.command_output = text.clone();
+            }
+            IPCRequest::ProgressInfo { text, percent } => {
+                self.message = Some(text.clone());
+                self.progress = Some(*percent);
+                self.severity = Severity::Info;
+            }
+            IPCRequest::Info { text } => self.set_message(text, Severity::Info),
+            IPCRequest::ReadyInfo { text } => self.set_message(text, Severity::Ready),
+            IPCRequest::Warning { text } => self.set_message(text, Severity::Warning),
+            IPCRequest::Error { text } => self.set_message(text, Severity::Error),
+            _ => {}
+        }
+    }
+
+    fn set_message(&mut self, text: &str, severity: Severity) {
+        self.message = Some(text.to_string());
+        self.progress = None;
+        self.severity = severity;
+    }
+
+    fn to_snapshot(&self) -> IPCRequest {
+        IPCRequest::Snapshot {
+            message: self.message.clone(),
+            severity: self.severity,
+            progress: self.progress,
+            command_output: self.command_output.clone(),
+        }
+    }
 }
 
 impl DisplayIPC {
     pub fn new(address: SocketAddr) -> Self {
-        Self { address }
+        let shared = Arc::new(Shared::default());
+        let worker_shared = shared.clone();
+
+        std::thread::spawn(move || run_connection_loop(address, worker_shared));
+
+        Self { shared }
     }
 
     pub fn send_info<S: Into<String>>(&self, text: S) -> anyhow::Result<()> {
@@ -27,7 +103,7 @@ impl DisplayIPC {
     }
 
     pub fn send_error<S: Into<String>>(&self, text: S) -> anyhow::Result<()> {
-        self.send_doc(IPCRequest::Warning { text: text.into() })?;
+        self.send_doc(IPCRequest::Error { text: text.into() })?;
         Ok(())
     }
 
@@ -49,18 +125,167 @@ impl DisplayIPC {
         Ok(())
     }
 
-    fn connect(&self) -> anyhow::Result<TcpStream> {
-        let stream = TcpStream::connect_timeout(&self.address, Duration::from_secs(1))?;
+    /// Queue the message to be sent by the connection thread
+    fn send_doc(&self, api_doc: IPCRequest) -> anyhow::Result<()> {
+        let mut outbox = self.shared.outbox.lock().unwrap();
+
+        outbox.screen.update(&api_doc);
+
+        if outbox.queue.len() >= MAX_QUEUE_LENGTH {
+            tracing::trace!("display ipc queue full, dropping oldest message");
+            outbox.queue.pop_front();
+        }
+
+        outbox.queue.push_back(api_doc);
+        self.shared.condvar.notify_one();
+
+        Ok(())
+    }
+}
+
+/// Connect to the display, send queued messages, and reconnect on failure
+fn run_connection_loop(address: SocketAddr, shared: Arc<Shared>) {
+    let mut next_id = 1;
+
+    loop {
+        match Connection::open(address, shared.clone()) {
+            Ok(mut connection) => {
+                tracing::info!("display ipc connected");
+
+                if let Err(error) = connection.send_queued(&shared, &mut next_id) {
+                    tracing::info!(?error, "display ipc disconnected");
+                }
+            }
+            Err(error) => {
+                tracing::trace!(?error, "display ipc connect");
+            }
+        }
+
+        std::thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    replies: Receiver<IPCRequest>,
+}
 
+impl Connection {
+    /// Connect and perform the handshake
+    ///
+    /// Replies are read by a background thread which also answers the
+    /// display's snapshot requests.
+    fn open(address: SocketAddr, shared: Arc<Shared>) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
         stream.set_nodelay(true)?;
 
-        Ok(stream)
+        let mut connection_stream = stream.try_clone()?;
+        write_message(
+            &mut connection_stream,
+            0,
+            IPCRequest::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )?;
+
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let mut buf = String::new();
+        reader.read_line(&mut buf)?;
+
+        match serde_json::from_str::<IPCMessage>(&buf)?.request {
+            IPCRequest::Hello { version } if version == PROTOCOL_VERSION => {}
+            request => anyhow::bail!("display rejected handshake: {request:?}"),
+        }
+
+        reader.get_ref().set_read_timeout(None)?;
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || loop {
+            buf.clear();
+
+            match reader.read_line(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => match serde_json::from_str::<IPCMessage>(&buf) {
+                    Ok(IPCMessage {
+                        request: IPCRequest::GetSnapshot,
+                        ..
+                    }) => {
+                        // Messages still in the queue are older than the snapshot
+                        let mut outbox = shared.outbox.lock().unwrap();
+                        let snapshot = outbox.screen.to_snapshot();
+                        outbox.queue.clear();
+                        outbox.queue.push_back(snapshot);
+                        shared.condvar.notify_one();
+                    }
+                    Ok(message) => {
+                        if sender.send(message.request).is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => tracing::debug!(?error, "display ipc invalid message"),
+                },
+            }
+        });
+
+        Ok(Self {
+            stream: connection_stream,
+            replies: receiver,
+        })
     }
 
-    fn send_doc(&self, api_doc: IPCRequest) -> anyhow::Result<()> {
-        let stream = self.connect()?;
-        serde_json::to_writer(stream, &api_doc)?;
+    /// Send messages from the queue until an error occurs
+    fn send_queued(&mut self, shared: &Shared, next_id: &mut u64) -> anyhow::Result<()> {
+        loop {
+            let request = {
+                let mut outbox = shared.outbox.lock().unwrap();
 
-        Ok(())
+                loop {
+                    match outbox.queue.pop_front() {
+                        Some(request) => break request,
+                        None => outbox = shared.condvar.wait(outbox).unwrap(),
+                    }
+                }
+            };
+
+            let id = *next_id;
+            *next_id += 1;
+
+            write_message(&mut self.stream, id, request)?;
+            self.wait_for_ack(id)?;
+        }
+    }
+
+    fn wait_for_ack(&self, id: u64) -> anyhow::Result<()> {
+        loop {
+            match self.replies.recv_timeout(REPLY_TIMEOUT) {
+                Ok(IPCRequest::Ack { message_id }) if message_id == id => return Ok(()),
+                Ok(IPCRequest::Nack { message_id, error }) if message_id == id => {
+                    tracing::warn!(id, error, "display rejected message");
+                    return Ok(());
+                }
+                Ok(request) => {
+                    tracing::trace!(?request, "display ipc unexpected reply");
+                }
+                Err(RecvTimeoutError::Timeout) => anyhow::bail!("timeout waiting for ack {id}"),
+                Err(RecvTimeoutError::Disconnected) => anyhow::bail!("connection closed"),
+            }
+        }
     }
 }
+
+impl Drop for Connection {
+    /// Close the socket so the reader thread's clone of the stream stops too
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn write_message<W: Write>(writer: &mut W, id: u64, request: IPCRequest) -> anyhow::Result<()> {
+    let mut buf = serde_json::to_vec(&IPCMessage { id, request })?;
+    buf.push(b'\n');
+    writer.write_all(&buf)?;
+
+    Ok(())
+}
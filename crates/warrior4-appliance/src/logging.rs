@@ -103,6 +103,60 @@ where
     Ok(exit_status)
 }
 
+/// Like [`monitor_command_output`] but only stderr is given to the callback
+/// and stdout is returned separately
+pub fn monitor_command_stderr<C>(
+    command: &mut Command,
+    output_callback: C,
+) -> anyhow::Result<(ExitStatus, Vec<u8>)>
+where
+    C: Fn(&[u8]) + Send,
+{
+    let mut output = Vec::new();
+
+    let program = command.get_program().to_owned();
+    let args = command
+        .get_args()
+        .map(|s| s.to_owned())
+        .collect::<Vec<OsString>>();
+
+    let mut child = command
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+
+    let out_handle = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf)?;
+        Ok::<Vec<u8>, std::io::Error>(buf)
+    });
+
+    let mut buf = vec![0; 4096];
+
+    loop {
+        let len = stderr.read(&mut buf)?;
+
+        if len == 0 {
+            break;
+        }
+
+        output.extend_from_slice(&buf[..len]);
+        output_callback(&output);
+    }
+
+    let stdout = out_handle.join().unwrap()?;
+    let exit_status = child.wait()?;
+    let output = String::from_utf8_lossy(&output);
+    let stdout_text = String::from_utf8_lossy(&stdout);
+
+    tracing::debug!(?program, ?args, %output, stdout = %stdout_text, %exit_status, "command output");
+    tracing::info!(?program, ?args, %exit_status, "command exited");
+
+    Ok((exit_status, stdout))
+}
+
 fn read_std_streams<C>(
     mut stdout: std::process::ChildStdout,
     mut stderr: std::process::ChildStderr,
@@ -2,10 +2,19 @@
 
 mod config;
 mod container;
+mod control;
+mod files;
 mod ipc;
 mod logging;
 mod manager;
+mod manifest;
 mod net;
+mod network_check;
+mod patch;
+mod phase;
+mod rollout;
+mod self_update;
+mod signature;
 mod state;
 
 use std::path::{Path, PathBuf};
@@ -44,9 +53,17 @@ fn wrapped_main() -> anyhow::Result<()> {
         exit_if_not_vm()?;
     }
 
-    let config = config::load_config(&args.config).context("loading config failed")?;
+    let paths = config::load_startup_paths(&args.config).context("loading config failed")?;
+
+    logging::set_up_logging(&paths.log_path).context("logging setup failed")?;
 
-    logging::set_up_logging(&config.log_path).context("logging setup failed")?;
+    match self_update::check_pending(&paths.state_path) {
+        Ok(true) => return Err(self_update::exec_self()),
+        Ok(false) => {}
+        Err(error) => tracing::error!(?error, "checking the pending self-update failed"),
+    }
+
+    let config = config::load_config(&args.config).context("loading config failed")?;
 
     let mut manager = manager::Manager::new(config);
     manager.run()?;
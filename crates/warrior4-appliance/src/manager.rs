@@ -1,21 +1,52 @@
 //! Docker container manager and system maintenance
 use std::{
+    io::Write,
     os::unix::prelude::OpenOptionsExt,
+    path::{Path, PathBuf},
     process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
 use anyhow::Context;
-
-use crate::{config::AppConfig, ipc::DisplayIPC, state::State};
+use warrior4_appliance_display::control::{Request as ControlRequest, Response as ControlResponse};
+
+use crate::{
+    config::{AppConfig, ChannelConfig, ContainerRole},
+    container::{ContainerStatus, DockerClient, HealthStatus},
+    control::{ControlCommand, ControlServer},
+    files,
+    ipc::DisplayIPC,
+    manifest::{self, Manifest},
+    network_check::NetworkReport,
+    patch::{self, PatchFile, PatchOutcome, PatchRecord, PATCH_VERSION_PATH, STAGING_DIR},
+    phase::{FailureAction, Phase, PhaseOutcome, PhaseRecord},
+    rollout,
+    self_update::{self, Release, SelfUpdateOutcome, SelfUpdateRecord},
+    state::State,
+};
 
 const PATCH_FILE_PATH: &str = "/tmp/warrior4-appliance-patch";
 const MAX_UNHEALTHY_TIME: Duration = Duration::from_secs(60 * 15);
+const DOCKER_READY_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+const DOCKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Manager {
     config: AppConfig,
     state: State,
     display_ipc: DisplayIPC,
+    docker: DockerClient,
+    shared_state: Arc<Mutex<State>>,
+    control_sender: Sender<ControlCommand>,
+    control_receiver: Mutex<Receiver<ControlCommand>>,
+    /// Set while waiting for control commands so the control server can
+    /// refuse commands at once during other work
+    control_accepting: Arc<AtomicBool>,
+    state_loaded: bool,
     payload_crashed: bool,
     unheathy_timestamp: Option<Instant>,
 }
@@ -24,10 +55,19 @@ impl Manager {
     pub fn new(config: AppConfig) -> Self {
         let state = State::new();
         let display_ipc = DisplayIPC::new(config.display_ipc_address);
+        let docker = DockerClient::new(&config.docker_socket_path);
+        let shared_state = Arc::new(Mutex::new(state.clone()));
+        let (control_sender, control_receiver) = std::sync::mpsc::channel();
         Self {
             config,
             state,
             display_ipc,
+            docker,
+            shared_state,
+            control_sender,
+            control_receiver: Mutex::new(control_receiver),
+            control_accepting: Arc::new(AtomicBool::new(false)),
+            state_loaded: false,
             payload_crashed: false,
             unheathy_timestamp: None,
         }
@@ -35,23 +75,20 @@ impl Manager {
 
     /// Start up, monitor the system and containers
     pub fn run(&mut self) -> anyhow::Result<()> {
-        match self.init_system_with_retry() {
-            Ok(_) => {
-                tracing::debug!("initialization completed")
-            }
-            Err(error) => {
-                tracing::debug!("initialization failed");
-                self.reboot_due_to_error(format!("{error:#}"))?;
-            }
-        }
+        self.start_control_server();
 
-        match self.monitor_system_with_retry() {
-            Ok(_) => {
-                tracing::debug!("monitor completed")
-            }
-            Err(error) => {
-                tracing::debug!("monitor failed");
-                self.reboot_due_to_error(format!("{error:#}"))?;
+        let mut phase = Some(Phase::FIRST);
+
+        while let Some(current) = phase {
+            match self.run_phase(current) {
+                Ok(_) => {
+                    phase = current.next();
+                }
+                Err(error) => {
+                    tracing::debug!(phase = ?current, "phase failed");
+                    self.reboot_due_to_error(format!("{error:#}"))?;
+                    break;
+                }
             }
         }
 
@@ -60,119 +97,285 @@ impl Manager {
         Ok(())
     }
 
-    /// Run the initialization steps with retries
-    fn init_system_with_retry(&mut self) -> anyhow::Result<()> {
-        for index in 0..10 {
-            match self.init_system() {
+    /// Run a phase, retrying it according to its policy
+    ///
+    /// An error is returned only if the phase failed and its policy says to reboot.
+    fn run_phase(&mut self, phase: Phase) -> anyhow::Result<()> {
+        let _span = tracing::info_span!("phase", ?phase).entered();
+        let policy = phase.policy();
+
+        if let Some(last_passed) = self.state.phase_last_passed.get(&phase).copied() {
+            if policy.skips_after_pass(last_passed, chrono::Utc::now()) {
+                tracing::info!(?last_passed, "skipping recently passed phase");
+                self.record_phase(
+                    phase,
+                    0,
+                    chrono::Utc::now(),
+                    PhaseOutcome::Skipped {
+                        reason: format!("passed at {last_passed}"),
+                    },
+                );
+                return Ok(());
+            }
+        }
+
+        tracing::info!("entering phase");
+        self.state.phase = Some(phase);
+
+        // Saved now so the state file shows the phase if it hangs or restarts the machine
+        if let Err(error) = self.save_state() {
+            tracing::error!(?error, "save state");
+        }
+
+        let phase_start = Instant::now();
+        let mut attempt = 1;
+
+        let error = loop {
+            let attempt_start = chrono::Utc::now();
+
+            match self.run_phase_step(phase) {
                 Ok(_) => {
+                    self.record_phase(phase, attempt, attempt_start, PhaseOutcome::Passed);
                     return Ok(());
                 }
                 Err(error) => {
-                    tracing::error!(?error, "initialization error");
-                    let error_message = format!("A problem occurred during start up\n\n{error:#}");
-
-                    let sleep_time = 60 * 2u64.pow(index);
-                    tracing::info!(sleep_time, "sleeping");
-                    self.countdown_timer(&error_message, sleep_time, CountdownKind::Retry);
+                    tracing::error!(?error, attempt, "phase error");
+                    self.record_phase(
+                        phase,
+                        attempt,
+                        attempt_start,
+                        PhaseOutcome::Failed {
+                            error: format!("{error:#}"),
+                        },
+                    );
+
+                    let delay = policy.delay_before(attempt + 1);
+                    let timed_out = policy
+                        .retry_time_limit
+                        .is_some_and(|limit| phase_start.elapsed() + delay > limit);
+
+                    if attempt >= policy.max_attempts || timed_out {
+                        break error;
+                    }
+
+                    let error_message = format!(
+                        "A problem occurred: {}\n\n{error:#}\n\nAttempt {attempt} of {}.",
+                        phase.description(),
+                        policy.max_attempts
+                    );
+                    tracing::info!(sleep_time = delay.as_secs(), "sleeping");
+                    self.countdown_timer(&error_message, delay.as_secs(), CountdownKind::Retry);
+
+                    attempt += 1;
                 }
             }
+        };
+
+        match policy.on_failure {
+            FailureAction::Reboot => Err(error.context(format!("{} failed", phase.description()))),
+            FailureAction::Skip => {
+                tracing::warn!(?error, "skipping phase");
+                self.display_warning(format!(
+                    "{} is skipped because it is unavailable or has an error. It will be retried later.\n\nError: {error:#}",
+                    phase.description()
+                ));
+                std::thread::sleep(Duration::from_secs(5));
+
+                Ok(())
+            }
         }
+    }
 
-        anyhow::bail!("initialization failed repeatedly")
+    /// Run a single attempt of the phase
+    fn run_phase_step(&mut self, phase: Phase) -> anyhow::Result<()> {
+        match phase {
+            Phase::LoadState => self.load_state().context("loading system state failed"),
+            Phase::WaitForDocker => self.wait_for_docker(),
+            Phase::CheckConnectivity => self.check_internet_connectivity(),
+            Phase::Patch => self.patch_system(),
+            Phase::SelfUpdate => self.update_self(),
+            Phase::CreateContainers => self
+                .create_containers()
+                .context("creating the containers failed"),
+            Phase::UpdateContainers => self
+                .update_containers()
+                .context("updating the containers failed"),
+            Phase::StartContainers => self
+                .start_containers()
+                .context("starting the containers failed"),
+            Phase::WaitForPayload => {
+                self.wait_for_payload()
+                    .context("starting the web interface failed")?;
+                self.finish_self_update()?;
+                self.show_ready_message();
+
+                Ok(())
+            }
+            Phase::Monitor => self.monitor_system(),
+        }
     }
 
-    /// Run the initialization steps that creates and starts up the containers
-    fn init_system(&mut self) -> anyhow::Result<()> {
-        let _span = tracing::info_span!("initialization");
+    /// Add the phase attempt to the state history and save it
+    fn record_phase(
+        &mut self,
+        phase: Phase,
+        attempt: u32,
+        started: chrono::DateTime<chrono::Utc>,
+        outcome: PhaseOutcome,
+    ) {
+        self.state.record_phase(PhaseRecord {
+            boot: self.state.boot_count,
+            phase,
+            attempt,
+            started,
+            finished: chrono::Utc::now(),
+            outcome,
+        });
+
+        if let Err(error) = self.save_state() {
+            tracing::error!(?error, "save state");
+        }
+    }
 
-        self.wait_for_docker()?;
-        self.check_internet_connectivity()?;
+    /// Run the system and container monitoring steps in a loop
+    fn monitor_system(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.check_containers()
+                .context("checking the containers failed")?;
+            self.process_control_commands(Duration::from_secs(10), false);
+        }
+    }
+
+    /// Listen for commands from the control socket
+    fn start_control_server(&self) {
+        let server = ControlServer::new(
+            &self.config,
+            self.shared_state.clone(),
+            self.control_sender.clone(),
+            self.control_accepting.clone(),
+        );
+
+        if let Err(error) = server.spawn() {
+            tracing::error!(?error, "control server failed to start");
+        }
+    }
 
-        self.load_state().context("loading system state failed")?;
+    /// Wait for and run control commands for the given duration
+    ///
+    /// If `power_only` is true, only reboot and poweroff commands are run
+    /// and the others are refused.
+    fn process_control_commands(&mut self, duration: Duration, power_only: bool) {
+        let deadline = Instant::now() + duration;
 
-        if let Err(error) = self.patch_system() {
-            tracing::warn!(?error, "skipping patch system");
-            self.display_warning(format!(
-                "Patching is skipped because it is unavailable or has an error. It will be retried later.\n\nError: {error:#}"
-            ));
-            std::thread::sleep(Duration::from_secs(5));
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                break;
+            }
+
+            self.control_accepting.store(true, Ordering::SeqCst);
+            let command = self
+                .control_receiver
+                .lock()
+                .unwrap()
+                .recv_timeout(remaining);
+            self.control_accepting.store(false, Ordering::SeqCst);
+
+            if let Ok(command) = command {
+                self.run_control_command(command, power_only);
+            }
         }
+    }
 
-        self.create_containers()
-            .context("creating the containers failed")?;
+    /// Run a command received from the control socket
+    fn run_control_command(&mut self, command: ControlCommand, power_only: bool) {
+        let request = command.request;
+        let is_power = matches!(request, ControlRequest::Reboot | ControlRequest::Poweroff);
 
-        if let Err(error) = self
-            .update_containers()
-            .context("updating the containers failed")
-        {
-            tracing::warn!(?error, "skipping updating containers");
-            self.display_warning(format!("{error:#}"));
-            std::thread::sleep(Duration::from_secs(5));
+        if power_only && !is_power {
+            let _ = command.reply.send(ControlResponse::Error {
+                message: "The command is unavailable until the system has started up".to_string(),
+            });
+            return;
         }
 
-        self.start_containers()
-            .context("starting the containers failed")?;
-        self.wait_for_payload()
-            .context("starting the web interface failed")?;
-        self.show_ready_message();
+        let message = match request {
+            ControlRequest::Status => unreachable!("handled by the control server"),
+            ControlRequest::RestartPayload => "Restarting the payload container",
+            ControlRequest::RunUpdater => "Updating the containers",
+            ControlRequest::RunPatch => "Patching the system",
+            ControlRequest::Reboot => "Rebooting",
+            ControlRequest::Poweroff => "Powering off",
+            ControlRequest::SetChannel { .. } => "Selecting the release channel",
+        };
 
-        tracing::debug!("completed");
+        let response = ControlResponse::Accepted {
+            message: message.to_string(),
+        };
 
-        Ok(())
-    }
+        if command.reply.send(response).is_err() {
+            tracing::debug!(?request, "control client gave up, not running command");
+            return;
+        }
 
-    /// Run the system and containers monitoring steps with retries
-    fn monitor_system_with_retry(&mut self) -> anyhow::Result<()> {
-        for index in 0..10 {
-            match self.monitor_system() {
-                Ok(_) => {
-                    return Ok(());
-                }
-                Err(error) => {
-                    tracing::error!(?error, "run monitor steps error");
-                    let error_message = format!("A problem occurred\n\n{error}");
+        tracing::info!(?request, "running control command");
+
+        let result = match &request {
+            ControlRequest::Status => Ok(()),
+            ControlRequest::RestartPayload => self.restart_payload(),
+            ControlRequest::RunUpdater => self
+                .update_containers()
+                .and_then(|_| self.start_containers()),
+            ControlRequest::RunPatch => self.patch_system(),
+            ControlRequest::Reboot => self.reboot_gracefully(),
+            ControlRequest::Poweroff => self.poweroff_gracefully(),
+            ControlRequest::SetChannel { channel } => self.set_channel(channel),
+        };
 
-                    let sleep_time = 60 * 2u64.pow(index);
-                    tracing::info!(sleep_time, "sleeping");
-                    self.countdown_timer(&error_message, sleep_time, CountdownKind::Retry);
-                }
-            }
+        if let Err(error) = result {
+            tracing::error!(?error, ?request, "control command failed");
+            self.display_warning(format!("{message} failed\n\nError: {error:#}"));
+            std::thread::sleep(Duration::from_secs(5));
         }
 
-        anyhow::bail!("monitoring system failed")
+        if !power_only {
+            self.show_ready_message();
+        }
     }
 
-    /// Run the system and container monitoring steps in a loop
-    fn monitor_system(&mut self) -> anyhow::Result<()> {
-        let _span = tracing::info_span!("monitor system");
+    /// Restart the payload container and reset its crash detection
+    fn restart_payload(&mut self) -> anyhow::Result<()> {
+        let name = self.config.payload_container().name.clone();
 
-        loop {
-            self.check_containers()
-                .context("checking the containers failed")?;
-            std::thread::sleep(Duration::from_secs(10));
-        }
+        self.display_info(format!("Restarting container {name}"));
+        self.docker.restart_container(&name)?;
+        self.run_post_start_command()?;
+
+        self.payload_crashed = false;
+        self.unheathy_timestamp = None;
+
+        Ok(())
     }
 
-    /// Wait for the Docker daemon to start
+    /// Check whether the Docker daemon is ready
     fn wait_for_docker(&self) -> anyhow::Result<()> {
         tracing::info!("wait for docker");
         self.display_info("Waiting for Docker to be ready");
 
-        for _index in 0..=100 {
-            let mut command = Command::new("docker");
-            command.arg("version");
+        // Docker is usually still starting up, so not being ready is not an error yet
+        let deadline = Instant::now() + DOCKER_READY_TIMEOUT;
 
-            let output = crate::logging::log_command_output(&mut command)?;
-
-            if output.status.success() {
-                return Ok(());
+        loop {
+            match self.docker.ping() {
+                Ok(_) => return Ok(()),
+                Err(error) if Instant::now() < deadline => {
+                    tracing::debug!(?error, "docker is not ready yet");
+                    std::thread::sleep(DOCKER_POLL_INTERVAL);
+                }
+                Err(error) => return Err(error.context("Docker is not ready")),
             }
-
-            tracing::debug!("sleep for docker");
-            std::thread::sleep(Duration::from_secs(5));
         }
-
-        Err(anyhow::anyhow!("Timeout waiting for Docker"))
     }
 
     /// Show an error message and reboot the OS after a countdown
@@ -205,7 +408,7 @@ impl Manager {
     }
 
     /// Block and show a countdown timer indicating a retry
-    fn countdown_timer<S: AsRef<str>>(&self, text: S, seconds: u64, kind: CountdownKind) {
+    fn countdown_timer<S: AsRef<str>>(&mut self, text: S, seconds: u64, kind: CountdownKind) {
         let when = Instant::now() + Duration::from_secs(seconds);
 
         loop {
@@ -215,21 +418,20 @@ impl Manager {
                 break;
             }
 
-            let message = match kind {
-                CountdownKind::Retry => format!(
+            match kind {
+                CountdownKind::Retry => self.display_warning(format!(
                     "{}\n\nRetrying in {} seconds.",
                     text.as_ref(),
                     remaining.as_secs()
-                ),
-                CountdownKind::Reboot => format!(
+                )),
+                CountdownKind::Reboot => self.display_error(format!(
                     "{}\n\nRestarting the system in {} seconds.",
                     text.as_ref(),
                     remaining.as_secs()
-                ),
-            };
+                )),
+            }
 
-            self.display_error(message);
-            std::thread::sleep(Duration::from_secs(5));
+            self.process_control_commands(Duration::from_secs(5), true);
         }
     }
 
@@ -312,25 +514,51 @@ impl Manager {
         if self.config.state_path.try_exists()? {
             tracing::info!("loading state");
 
-            self.state = State::load(&self.config.state_path)?;
+            match State::load(&self.config.state_path) {
+                Ok(state) => self.state = state,
+                Err(error) if error.is::<serde_json::Error>() => {
+                    // Keep the damaged file for diagnosis but don't let it stop start up
+                    tracing::error!(?error, "state file is damaged, using new state");
+                    let damaged_path = self.config.state_path.with_extension("json.damaged");
+                    std::fs::rename(&self.config.state_path, damaged_path)?;
+                    self.state = State::new();
+                }
+                Err(error) => return Err(error),
+            }
         } else {
-            tracing::info!("saving new state");
-
-            self.state.save(&self.config.state_path)?;
+            tracing::info!("using new state");
         }
 
+        self.state_loaded = true;
+        self.state.boot_count += 1;
+        self.state.phase = Some(Phase::LoadState);
+        self.save_state()?;
+
         Ok(())
     }
 
     /// Save application state to disk
     fn save_state(&mut self) -> anyhow::Result<()> {
-        tracing::info!("saving state");
+        // Don't overwrite the state on disk with a blank state if loading failed
+        if !self.state_loaded {
+            tracing::debug!("state not loaded, not saving state");
+            return Ok(());
+        }
+
+        self.publish_state();
+
+        tracing::debug!("saving state");
 
         self.state.save(&self.config.state_path)?;
 
         Ok(())
     }
 
+    /// Update the copy of the state that is shown by the control socket
+    fn publish_state(&self) {
+        *self.shared_state.lock().unwrap() = self.state.clone();
+    }
+
     /// Check internet connectivity.
     ///
     /// This is intended only as a basic start up check for DNS problems
@@ -339,120 +567,541 @@ impl Manager {
         tracing::info!("checking internet connectivity");
         self.display_info("Checking internet connectivity");
 
-        for index in 0..=60 {
-            let mut command = Command::new("warrior4-network-check");
+        let mut command = Command::new("warrior4-network-check");
+        command.args(["--format", "json"]);
 
-            let status = crate::logging::monitor_command_output(&mut command, |output| {
-                let text = String::from_utf8_lossy(output);
-                self.display_command_output(text);
-            })?;
+        let (status, stdout) = crate::logging::monitor_command_stderr(&mut command, |output| {
+            let text = String::from_utf8_lossy(output);
+            self.display_command_output(text);
+        })?;
 
-            if status.success() {
-                std::thread::sleep(Duration::from_secs(5));
-                self.display_command_output("");
-                return Ok(());
+        if !status.success() {
+            let report = NetworkReport::parse(&String::from_utf8_lossy(&stdout));
+
+            match report {
+                Ok(report) if !report.explain().is_empty() => {
+                    anyhow::bail!(
+                        "internet connectivity check failed\n\n{}",
+                        report.explain().join("\n\n")
+                    );
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    tracing::debug!(?error, "network check report parse");
+                }
             }
 
-            tracing::debug!("sleep for check internet connectivity");
-            std::thread::sleep(Duration::from_secs(5 + index * 60));
+            anyhow::bail!("internet connectivity check failed");
         }
 
-        Err(anyhow::anyhow!("internet connectivity check failed"))
-    }
+        if let Ok(report) = NetworkReport::parse(&String::from_utf8_lossy(&stdout)) {
+            let warnings = report.warnings();
 
-    /// Download and an execute a file to modify the system
-    fn patch_system(&self) -> anyhow::Result<()> {
-        if let Some(url) = &self.config.patch_script_url {
-            self.download_patch_file(url)?;
+            if !warnings.is_empty() {
+                tracing::warn!(?warnings, "network check");
+                self.display_warning(warnings.join("\n\n"));
+            }
+        }
 
-            tracing::info!("executing patch file");
-            self.display_info("Patching the system");
+        std::thread::sleep(Duration::from_secs(5));
+        self.display_command_output("");
+
+        Ok(())
+    }
 
-            let mut command = std::process::Command::new(PATCH_FILE_PATH);
-            let status = crate::logging::monitor_command_output(&mut command, |output| {
-                let text = String::from_utf8_lossy(output);
-                self.display_command_output(crate::logging::get_last_line(&text));
-            })?;
+    /// Download and an execute a file or install the files of a manifest to modify the system
+    ///
+    /// Patches with a version that is not newer than the applied version, or
+    /// unversioned patches that were applied before, are not run again.
+    fn patch_system(&mut self) -> anyhow::Result<()> {
+        let (channel_name, channel) = self.selected_channel();
 
-            if !status.success() {
-                anyhow::bail!("patch program exited with exit status {}", status);
-            }
+        let Some(url) = channel.patch_url() else {
+            return Ok(());
+        };
 
-            tracing::info!("patching success");
-            self.display_command_output("");
+        if self.config.patch_public_keys.is_empty() {
+            tracing::info!("no patch public keys are configured, not checking for a patch");
+            return Ok(());
         }
 
-        Ok(())
-    }
+        tracing::info!(channel = channel_name, url, "checking for a patch");
 
-    /// Download the patch file to disk and make it executable
-    fn download_patch_file(&self, url: &str) -> anyhow::Result<()> {
-        tracing::info!("downloading patch file");
-        self.display_info("Downloading system patch file");
+        let signature_url = channel.patch_signature_url().expect("patch URL is set");
+        let data = self.download_patch_file(url, &signature_url)?;
+        let patch = if channel.patch_manifest_url.is_some() {
+            PatchFile::from_manifest(data)?
+        } else {
+            PatchFile::new(data)?
+        };
+        let installed_version = self.installed_patch_version();
+
+        tracing::info!(
+            version = ?patch.version,
+            installed_version,
+            sha256 = patch.sha256,
+            "downloaded patch file"
+        );
+
+        if patch.is_applied(installed_version, &self.state) {
+            tracing::info!("patch is already applied");
+            return Ok(());
+        }
 
-        let mut response = reqwest::blocking::get(url)?;
+        if !self.in_rollout(&channel)? {
+            return Ok(());
+        }
 
-        tracing::debug!(status_code = %response.status(), "patch file response");
+        // Saved before running because the patch may restart the machine
+        self.state.record_patch(PatchRecord {
+            boot: self.state.boot_count,
+            version: patch.version,
+            sha256: patch.sha256.clone(),
+            started: chrono::Utc::now(),
+            finished: None,
+            outcome: PatchOutcome::Running,
+        });
+        self.save_state()?;
+
+        self.display_info("Patching the system");
+
+        let result = match &patch.manifest {
+            Some(manifest) => self.install_manifest(manifest),
+            None => self.run_patch_file(&patch.data),
+        };
 
-        if !response.status().is_success() {
-            anyhow::bail!("download patch file failed: {}", response.status());
+        match &result {
+            Ok(_) => {
+                tracing::info!("patching success");
+                self.state.finish_patch(PatchOutcome::Applied);
+
+                if let Some(version) = patch.version {
+                    if let Err(error) =
+                        patch::write_installed_version(Path::new(PATCH_VERSION_PATH), version)
+                    {
+                        tracing::warn!(?error, "could not write patch version file");
+                    }
+                }
+            }
+            Err(error) => self.state.finish_patch(PatchOutcome::Failed {
+                error: format!("{error:#}"),
+            }),
         }
 
+        self.save_state()?;
+        self.display_command_output("");
+
+        result
+    }
+
+    /// Returns the version of the applied patch from the state or the image's patch version file
+    fn installed_patch_version(&self) -> u64 {
+        let file_version = match patch::read_installed_version(Path::new(PATCH_VERSION_PATH)) {
+            Ok(version) => version,
+            Err(error) => {
+                tracing::warn!(?error, "could not read patch version file");
+                0
+            }
+        };
+
+        file_version.max(self.state.patch_version.unwrap_or_default())
+    }
+
+    /// Write the patch script to disk as an executable and run it
+    fn run_patch_file(&self, data: &[u8]) -> anyhow::Result<()> {
         let mut patch_file = std::fs::File::options()
             .create(true)
             .write(true)
             .truncate(true)
             .mode(0o755)
             .open(PATCH_FILE_PATH)?;
-        response.copy_to(&mut patch_file)?;
+        patch_file.write_all(data)?;
         patch_file.sync_all()?;
+        drop(patch_file);
+
+        tracing::info!("executing patch file");
+
+        let mut command = std::process::Command::new(PATCH_FILE_PATH);
+        let status = crate::logging::monitor_command_output(&mut command, |output| {
+            let text = String::from_utf8_lossy(output);
+            self.display_command_output(crate::logging::get_last_line(&text));
+        })?;
+
+        if !status.success() {
+            anyhow::bail!("patch program exited with exit status {}", status);
+        }
 
         Ok(())
     }
 
-    /// Create all the Docker containers (but do not start them)
-    fn create_containers(&self) -> anyhow::Result<()> {
-        let containers = [
-            (
-                &self.config.watchtower_name,
-                &self.config.watchtower_creator,
-            ),
-            (
-                &self.config.watchtower_run_once_name,
-                &self.config.watchtower_run_once_creator,
-            ),
-            (&self.config.payload_name, &self.config.payload_creator),
-        ];
-
-        for (index, (name, creator)) in containers.iter().enumerate() {
-            let status = crate::container::get_container_status(name);
-
-            tracing::debug!(name, status, "queried container status");
-
-            if !status.is_empty() {
-                continue;
+    fn install_manifest(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        tracing::info!(files = manifest.files.len(), "installing patch manifest");
+
+        manifest::install(manifest, Path::new(STAGING_DIR), |text| {
+            tracing::info!(text, "patch progress");
+            self.display_command_output(text);
+        })
+    }
+
+    /// Download the patch file and verify its signature
+    fn download_patch_file(&mut self, url: &str, signature_url: &str) -> anyhow::Result<Vec<u8>> {
+        tracing::info!("downloading patch file");
+        self.display_info("Downloading system patch file");
+
+        let data = crate::net::download(url).context("download patch file failed")?;
+
+        if let Err(error) = self.verify_signature(&data, signature_url) {
+            tracing::error!(?error, "patch file refused");
+            self.display_error(format!(
+                "The system patch was refused because it is not signed by a trusted key.\n\n{error:#}"
+            ));
+
+            self.state.record_patch(PatchRecord {
+                boot: self.state.boot_count,
+                version: None,
+                sha256: files::sha256_hex(&data),
+                started: chrono::Utc::now(),
+                finished: Some(chrono::Utc::now()),
+                outcome: PatchOutcome::Refused {
+                    error: format!("{error:#}"),
+                },
+            });
+            self.save_state()?;
+            std::thread::sleep(Duration::from_secs(5));
+
+            return Err(error.context("patch file refused"));
+        }
+
+        tracing::info!("patch signature verified");
+
+        Ok(data)
+    }
+
+    /// Returns the name and URLs of the selected release channel
+    fn selected_channel(&self) -> (String, ChannelConfig) {
+        let name = self
+            .state
+            .channel
+            .clone()
+            .filter(|name| self.config.channel_names().contains(name))
+            .unwrap_or_else(|| self.config.default_channel.clone());
+        let channel = self.config.channel(&name);
+
+        (name, channel)
+    }
+
+    /// Returns whether the machine is in the staged rollout of the channel
+    fn in_rollout(&self, channel: &ChannelConfig) -> anyhow::Result<bool> {
+        let percent = rollout::fetch_percent(channel.rollout_url.as_deref())
+            .context("download rollout file failed")?;
+        if rollout::includes(&self.state.uuid, percent) {
+            Ok(true)
+        } else {
+            let bucket = rollout::bucket(&self.state.uuid);
+            tracing::info!(bucket, percent, "not in the rollout yet");
+            Ok(false)
+        }
+    }
+
+    /// Select the release channel for patches and updates
+    fn set_channel(&mut self, channel: &str) -> anyhow::Result<()> {
+        if !self
+            .config
+            .channel_names()
+            .iter()
+            .any(|name| name == channel)
+        {
+            anyhow::bail!("unknown release channel {channel}");
+        }
+
+        tracing::info!(channel, "selecting release channel");
+
+        self.state.channel = Some(channel.to_string());
+        // Check the new channel at the next start instead of an hour later
+        self.state.phase_last_passed.remove(&Phase::Patch);
+        self.state.phase_last_passed.remove(&Phase::SelfUpdate);
+        self.save_state()?;
+
+        self.display_info(format!(
+            "Selected the {channel} release channel. Its patches and updates are installed the next time the system starts."
+        ));
+        std::thread::sleep(Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    /// Download the minisign signature and check it against the trusted public keys
+    fn verify_signature(&self, data: &[u8], signature_url: &str) -> anyhow::Result<()> {
+        tracing::info!(signature_url, "downloading signature");
+
+        let signature = crate::net::download(signature_url).context("download signature failed")?;
+        let signature = String::from_utf8(signature)?;
+
+        crate::signature::verify(data, &signature, &self.config.patch_public_keys)
+    }
+
+    /// Update the appliance binaries if the release file lists different ones
+    ///
+    /// The manager replaces its process with the new binary if its own binary was replaced.
+    fn update_self(&mut self) -> anyhow::Result<()> {
+        let (channel_name, channel) = self.selected_channel();
+
+        let Some(url) = channel.self_update_url.clone() else {
+            return Ok(());
+        };
+
+        if self.config.patch_public_keys.is_empty() {
+            tracing::info!("no patch public keys are configured, not checking for a self-update");
+            return Ok(());
+        }
+
+        if self.state.pending_self_update().is_some() {
+            tracing::info!("self-update is pending, not checking for another");
+            return Ok(());
+        }
+
+        tracing::info!(channel = channel_name, url, "checking for a self-update");
+        self.display_info("Checking for appliance software updates");
+
+        let data = crate::net::download(&url).context("download release file failed")?;
+        self.verify_signature(&data, &format!("{url}.minisig"))
+            .context("release file refused")?;
+        let release = Release::parse(&data)?;
+        let outdated = release.outdated_binaries();
+
+        if outdated.is_empty() {
+            tracing::info!(version = release.version, "binaries are up to date");
+            return Ok(());
+        }
+
+        if !self.in_rollout(&channel)? {
+            return Ok(());
+        }
+
+        tracing::info!(version = release.version, "updating binaries");
+        self.display_info(format!(
+            "Updating the appliance software to version {}",
+            release.version
+        ));
+
+        let paths = outdated
+            .iter()
+            .map(|binary| binary.path.clone())
+            .collect::<Vec<_>>();
+        let mut services = Vec::new();
+
+        for binary in &outdated {
+            if !services.contains(&binary.service) {
+                services.push(binary.service.clone());
             }
+        }
 
-            tracing::info!(name, ?creator, "create container");
+        // The update only finishes once this manager binary is running
+        let manager_sha256 = match outdated
+            .iter()
+            .find(|binary| binary.service == self_update::MANAGER_SERVICE)
+        {
+            Some(binary) => binary.sha256.clone(),
+            None => self_update::running_manager_sha256()?,
+        };
 
-            let percent = (index as f32 / containers.len() as f32 * 100.0) as u8;
-            self.display_progress(
-                format!(
-                    "Downloading and creating container {name}\n\nPlease wait. This may take a while."
-                ),
-                percent,
+        let mut record = SelfUpdateRecord {
+            boot: self.state.boot_count,
+            version: release.version.clone(),
+            started: chrono::Utc::now(),
+            finished: None,
+            paths,
+            services: services.clone(),
+            starts: 0,
+            manager_sha256: Some(manager_sha256),
+            outcome: SelfUpdateOutcome::Pending,
+        };
+
+        let result = self_update::install(&outdated, Path::new(STAGING_DIR), |text| {
+            tracing::info!(text, "self-update progress");
+            self.display_command_output(text);
+        });
+        self.display_command_output("");
+
+        if let Err(error) = result {
+            record.finished = Some(chrono::Utc::now());
+            record.outcome = SelfUpdateOutcome::Failed {
+                error: format!("{error:#}"),
+            };
+            self.state.record_self_update(record);
+            self.save_state()?;
+
+            return Err(error);
+        }
+
+        self.state.record_self_update(record);
+        self.save_state()?;
+
+        self_update::restart_services(&services)?;
+
+        if services
+            .iter()
+            .any(|service| service == self_update::MANAGER_SERVICE)
+        {
+            self.display_info("Restarting the appliance manager");
+            let error = self_update::exec_self();
+
+            // Still running the previous binary, so the update can't finish
+            let outcome = SelfUpdateOutcome::Failed {
+                error: format!("{error:#}"),
+            };
+            if let Err(rollback_error) = self.roll_back_self_update(outcome) {
+                tracing::error!(?rollback_error, "rolling back the self-update failed");
+            }
+
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Mark a pending self-update as succeeded and remove the previous binaries
+    ///
+    /// The update stays pending if the updated manager binary is not the one
+    /// running. It is rolled back if a restarted service is not running.
+    fn finish_self_update(&mut self) -> anyhow::Result<()> {
+        let Some(record) = self.state.pending_self_update() else {
+            return Ok(());
+        };
+
+        let running_sha256 = self_update::running_manager_sha256()?;
+
+        if !record.is_running(&running_sha256) {
+            tracing::warn!(
+                version = record.version,
+                running_sha256,
+                "the updated manager is not running, self-update stays pending"
+            );
+            return Ok(());
+        }
+
+        let services = record.services.clone();
+
+        if let Err(error) = self_update::check_services(&services) {
+            tracing::error!(?error, "self-update failed, rolling back");
+            self.display_warning(format!(
+                "The appliance software update failed and is rolled back.\n\n{error:#}"
+            ));
+
+            let outcome = SelfUpdateOutcome::RolledBack {
+                reason: format!("{error:#}"),
+            };
+            self.roll_back_self_update(outcome)?;
+
+            if services
+                .iter()
+                .any(|service| service == self_update::MANAGER_SERVICE)
+            {
+                return Err(self_update::exec_self());
+            }
+
+            return Ok(());
+        }
+
+        let record = self
+            .state
+            .pending_self_update()
+            .expect("self-update is pending");
+
+        tracing::info!(version = record.version, "self-update succeeded");
+
+        record.finished = Some(chrono::Utc::now());
+        record.outcome = SelfUpdateOutcome::Succeeded;
+        manifest::remove_backups(record.paths.iter().map(PathBuf::as_path));
+
+        self.save_state()
+    }
+
+    /// Restore the previous binaries of the pending self-update and restart
+    /// the services other than the manager
+    fn roll_back_self_update(&mut self, outcome: SelfUpdateOutcome) -> anyhow::Result<()> {
+        let Some(record) = self.state.pending_self_update() else {
+            return Ok(());
+        };
+
+        let result = self_update::roll_back(record, outcome);
+        let services = record.services.clone();
+        self.save_state()?;
+        result?;
+
+        self_update::restart_services(&services)
+    }
+
+    /// Create all the Docker containers (but do not start them)
+    ///
+    /// Containers whose declaration changed since they were created are recreated.
+    fn create_containers(&mut self) -> anyhow::Result<()> {
+        let containers = self.config.containers.clone();
+
+        for (index, container) in containers.iter().enumerate() {
+            let name = &container.name;
+            let state = self.docker.inspect_container(name)?;
+            let spec_hash = container.spec_hash();
+            let stored_hash = self.state.container_spec_hashes.get(name);
+
+            tracing::debug!(
+                name,
+                ?state,
+                spec_hash,
+                ?stored_hash,
+                "queried container state"
             );
 
-            let mut command = Command::new(creator);
-            let status = crate::logging::monitor_command_output(&mut command, |output| {
-                let text = String::from_utf8_lossy(output);
-                self.display_command_output(crate::logging::get_last_line(&text));
-            })?;
+            let percent = (index as f32 / containers.len() as f32 * 100.0) as u8;
+
+            if state.is_some() {
+                if stored_hash == Some(&spec_hash) {
+                    continue;
+                }
+
+                // Containers created before spec hashes were stored may be
+                // stale, so they are recreated like changed ones
+                if stored_hash.is_none() {
+                    tracing::info!(name, "container has no stored spec hash");
+                } else {
+                    tracing::info!(name, "container spec changed");
+                }
+                self.display_progress(
+                    format!(
+                        "Recreating container {name} because its configuration changed\n\nPlease wait. This may take a while."
+                    ),
+                    percent,
+                );
+            } else {
+                self.display_progress(
+                    format!(
+                        "Downloading and creating container {name}\n\nPlease wait. This may take a while."
+                    ),
+                    percent,
+                );
+            }
+
+            self.docker
+                .pull_image(&container.image, |text| {
+                    self.display_command_output(text);
+                })
+                .with_context(|| format!("downloading image for container {name} failed"))?;
 
-            if !status.success() {
-                anyhow::bail!("container creator program exited with status {}", status);
+            if state.is_some() {
+                self.docker
+                    .remove_container(name)
+                    .with_context(|| format!("removing container {name} failed"))?;
             }
 
+            self.docker
+                .create_container(container)
+                .with_context(|| format!("creating container {name} failed"))?;
+
+            self.state
+                .container_spec_hashes
+                .insert(name.clone(), spec_hash);
+            self.save_state()?;
+
             self.display_command_output("");
         }
 
@@ -461,44 +1110,65 @@ impl Manager {
         Ok(())
     }
 
-    /// Start the Watchtower run-once container to force the containers to update
+    /// Run the updater containers (Watchtower with --run-once) to force the containers to update
     fn update_containers(&self) -> anyhow::Result<()> {
         tracing::info!("update containers");
         self.display_info("Updating containers\n\nPlease wait. This may take a while.");
 
-        let (output1, output2) =
-            crate::container::run_container_foreground(&self.config.watchtower_run_once_name)?;
+        for container in self.config.containers_with_role(ContainerRole::Updater) {
+            let name = &container.name;
+
+            // Auto removed updaters are gone after their first run
+            if self.docker.inspect_container(name)?.is_none() {
+                tracing::info!(name, "creating missing update container");
+                self.docker
+                    .pull_image(&container.image, |text| {
+                        self.display_command_output(text);
+                    })
+                    .with_context(|| format!("downloading image for container {name} failed"))?;
+                self.docker
+                    .create_container(container)
+                    .with_context(|| format!("creating container {name} failed"))?;
+            }
 
-        if !output1.status.success() || !output2.status.success() {
-            anyhow::bail!("update container program exited with failure");
-        } else {
-            Ok(())
+            let exit_code = self.docker.run_container_foreground(name)?;
+
+            if exit_code != 0 {
+                anyhow::bail!("update container {name} exited with exit code {exit_code}");
+            }
         }
+
+        Ok(())
     }
 
-    /// Start the Watchtower and payload containers
+    /// Start the service and payload containers
     fn start_containers(&self) -> anyhow::Result<()> {
         self.run_pre_start_command()?;
 
-        let containers = [&self.config.watchtower_name, &self.config.payload_name];
+        let containers = self
+            .config
+            .containers
+            .iter()
+            .filter(|container| container.role != ContainerRole::Updater)
+            .collect::<Vec<_>>();
 
-        for (index, name) in containers.iter().enumerate() {
+        for (index, container) in containers.iter().enumerate() {
+            let name = &container.name;
             let percent = (index as f32 / containers.len() as f32 * 100.0) as u8;
-            let status = crate::container::get_container_status(name);
+            let state = self
+                .docker
+                .inspect_container(name)?
+                .with_context(|| format!("container {name} does not exist"))?;
 
-            if status == "running" {
+            if state.status == ContainerStatus::Running {
                 tracing::debug!(name, "container already running");
                 continue;
             }
 
-            tracing::info!(name, "start container");
             self.display_progress(format!("Starting container {name}"), percent);
-
-            let output = crate::container::start_container(name)?;
-
-            if !output.status.success() {
-                anyhow::bail!("pre start command exited with status {}", output.status);
-            }
+            self.docker
+                .start_container(name)
+                .with_context(|| format!("starting container {name} failed"))?;
         }
 
         self.run_post_start_command()?;
@@ -609,26 +1279,35 @@ impl Manager {
 
     /// Check if the container exited with application error
     fn check_payload_has_exited_error(&mut self) -> anyhow::Result<bool> {
-        let status = crate::container::get_container_status(&self.config.payload_name);
-        let exit_code =
-            crate::container::get_container_exit_code(&self.config.payload_name).unwrap_or(0);
+        let Some(state) = self
+            .docker
+            .inspect_container(&self.config.payload_container().name)?
+        else {
+            return Ok(false);
+        };
 
-        tracing::trace!(status, exit_code, "check payload status");
+        tracing::trace!(?state, "check payload status");
 
         // https://docs.docker.com/engine/reference/run/#exit-status
 
-        Ok(status == "exited" && (1..=124).contains(&exit_code))
+        Ok(state.status == ContainerStatus::Exited && (1..=124).contains(&state.exit_code))
     }
 
     /// Check if the container is unhealthy
     fn check_payload_is_unhealthy(&mut self) -> anyhow::Result<bool> {
-        let status = crate::container::get_container_status(&self.config.payload_name);
-        let health =
-            crate::container::get_container_health(&self.config.payload_name).unwrap_or_default();
+        let Some(state) = self
+            .docker
+            .inspect_container(&self.config.payload_container().name)?
+        else {
+            self.unheathy_timestamp = None;
+            return Ok(false);
+        };
 
-        tracing::trace!(status, health, "check payload health");
+        tracing::trace!(?state, "check payload health");
 
-        if status == "running" && health == "unhealthy" {
+        if state.status == ContainerStatus::Running
+            && state.health_status() == Some(HealthStatus::Unhealthy)
+        {
             // The container's health check can be unreliable and may recover after some time
             if let Some(timestamp) = self.unheathy_timestamp {
                 Ok(timestamp.elapsed() > MAX_UNHEALTHY_TIME)
@@ -666,3 +1345,42 @@ enum CountdownKind {
     Retry,
     Reboot,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Config with patch and release URLs that can't be downloaded
+    fn config_with_keys(patch_public_keys: &str) -> AppConfig {
+        crate::config::parse_config(&format!(
+            r#"
+state_path = "/nonexistent/state.json"
+display_ipc_address = "127.0.0.1:1"
+patch_script_url = "http://127.0.0.1:1/patch.sh"
+self_update_url = "http://127.0.0.1:1/release.json"
+patch_public_keys = {patch_public_keys}
+payload_pre_start = "/bin/true"
+payload_post_start = "/bin/true"
+payload_wait_ready = "/bin/true"
+payload_reboot_check = "/bin/true"
+payload_poweroff_check = "/bin/true"
+payload_ready_message = "ready"
+reboot_on_payload_exit_error = false
+reboot_on_payload_unhealthy = false
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_no_public_keys_skips_download() {
+        let mut manager = Manager::new(config_with_keys("[]"));
+        manager.patch_system().unwrap();
+        manager.update_self().unwrap();
+
+        let key = r#"["RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3"]"#;
+        let mut manager = Manager::new(config_with_keys(key));
+        assert!(manager.patch_system().is_err());
+        assert!(manager.update_self().is_err());
+    }
+}
@@ -0,0 +1,524 @@
+//! Patch manifests that install several files at once
+//!
+//! Example:
+//!
+//! ```json
+//! {
+//!     "version": 3,
+//!     "files": [
+//!         {
+//!             "url": "https://example.org/warrior4-appliance",
+//!             "sha256": "<hex>",
+//!             "path": "/usr/bin/warrior4-appliance",
+//!             "mode": "755"
+//!         }
+//!     ],
+//!     "post_install": [["rc-service", "warrior4-appliance-display", "restart"]]
+//! }
+//! ```
+use std::{
+    collections::HashSet,
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::files::{download_verified, is_sha256_hex, suffixed_path, write_synced};
+
+/// Suffix of a verified file copied next to its destination before it is installed
+const NEW_SUFFIX: &str = "warrior4-new";
+/// Suffix of the replaced file kept until the patch or self-update succeeds
+pub const BACKUP_SUFFIX: &str = "warrior4-prev";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    /// Patch version, which must increase with every new manifest
+    pub version: u64,
+    pub files: Vec<ManifestFile>,
+    /// Commands run after the files are installed
+    #[serde(default)]
+    pub post_install: Vec<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestFile {
+    pub url: String,
+    /// Hex SHA-256 hash of the file
+    pub sha256: String,
+    /// Absolute destination path
+    pub path: PathBuf,
+    /// Octal file mode
+    #[serde(default = "default_mode")]
+    pub mode: String,
+}
+
+impl ManifestFile {
+    fn mode(&self) -> anyhow::Result<u32> {
+        u32::from_str_radix(&self.mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .with_context(|| format!("invalid mode {:?} of {}", self.mode, self.path.display()))
+    }
+}
+
+fn default_mode() -> String {
+    "644".to_string()
+}
+
+impl Manifest {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let manifest = serde_json::from_slice::<Manifest>(data).context("invalid manifest")?;
+        manifest.validate()?;
+
+        Ok(manifest)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut paths = HashSet::new();
+
+        for file in &self.files {
+            let path = file.path.display();
+
+            anyhow::ensure!(file.path.is_absolute(), "path {path} is not absolute");
+            anyhow::ensure!(
+                file.path.file_name().is_some(),
+                "path {path} is not a file path"
+            );
+            anyhow::ensure!(paths.insert(&file.path), "duplicate path {path}");
+            anyhow::ensure!(is_sha256_hex(&file.sha256), "invalid sha256 of {path}");
+            file.mode()?;
+        }
+
+        for command in &self.post_install {
+            anyhow::ensure!(!command.is_empty(), "empty post install command");
+        }
+
+        Ok(())
+    }
+}
+
+/// A file that was installed and how to undo it
+struct InstalledFile {
+    path: PathBuf,
+    /// The replaced file or `None` if the file did not exist
+    backup: Option<PathBuf>,
+}
+
+/// Download, verify, and install the manifest's files, then run its post install commands
+///
+/// Files are downloaded to the staging directory and copied next to their
+/// destination before any file is replaced. If any step fails, the replaced
+/// files are restored.
+pub fn install<P>(manifest: &Manifest, staging_dir: &Path, progress: P) -> anyhow::Result<()>
+where
+    P: FnMut(String),
+{
+    install_keeping_backups(manifest, staging_dir, progress)?;
+    remove_backups(manifest.files.iter().map(|file| file.path.as_path()));
+
+    Ok(())
+}
+
+/// Install like [`install`] but keep the replaced files so a later
+/// [`restore_backups`] can undo the installation
+pub fn install_keeping_backups<P>(
+    manifest: &Manifest,
+    staging_dir: &Path,
+    mut progress: P,
+) -> anyhow::Result<()>
+where
+    P: FnMut(String),
+{
+    let result = stage_files(manifest, staging_dir, &mut progress)
+        .and_then(|staged| prepare_files(manifest, &staged));
+    let _ = std::fs::remove_dir_all(staging_dir);
+
+    if let Err(error) = result {
+        remove_new_files(manifest);
+        return Err(error);
+    }
+
+    let mut installed = Vec::new();
+
+    for file in &manifest.files {
+        progress(format!("Installing {}", file.path.display()));
+
+        match install_file(&file.path) {
+            Ok(file) => installed.push(file),
+            Err(error) => {
+                undo_install(&installed);
+                remove_new_files(manifest);
+                return Err(error);
+            }
+        }
+    }
+
+    for command in &manifest.post_install {
+        progress(format!("Running {}", command.join(" ")));
+
+        if let Err(error) = run_command(command) {
+            undo_install(&installed);
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Move the kept files back over the installed files
+pub fn restore_backups<'a, I>(paths: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    for path in paths {
+        let backup_path = suffixed_path(path, BACKUP_SUFFIX);
+
+        if backup_path.exists() {
+            std::fs::rename(&backup_path, path)
+                .with_context(|| format!("restoring {} failed", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the kept files after the installation succeeded
+pub fn remove_backups<'a, I>(paths: I)
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    for path in paths {
+        let backup = suffixed_path(path, BACKUP_SUFFIX);
+
+        if let Err(error) = std::fs::remove_file(&backup) {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!(?error, ?backup, "could not remove backup");
+            }
+        }
+    }
+}
+
+/// Download the files to the staging directory and verify their hashes
+fn stage_files<P>(
+    manifest: &Manifest,
+    staging_dir: &Path,
+    progress: &mut P,
+) -> anyhow::Result<Vec<PathBuf>>
+where
+    P: FnMut(String),
+{
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(staging_dir)?;
+    }
+
+    std::fs::create_dir_all(staging_dir)?;
+
+    let mut staged = Vec::new();
+
+    for (index, file) in manifest.files.iter().enumerate() {
+        progress(format!("Downloading {}", file.url));
+        tracing::info!(url = file.url, path = ?file.path, "downloading patch artifact");
+
+        let data = download_verified(&file.url, &file.sha256)?;
+        let staged_path = staging_dir.join(index.to_string());
+        write_synced(&staged_path, &data)?;
+        staged.push(staged_path);
+    }
+
+    Ok(staged)
+}
+
+/// Copy the staged files next to their destinations so they can be renamed into place
+fn prepare_files(manifest: &Manifest, staged: &[PathBuf]) -> anyhow::Result<()> {
+    for (file, staged_path) in manifest.files.iter().zip(staged) {
+        let new_path = suffixed_path(&file.path, NEW_SUFFIX);
+
+        if let Some(dir) = file.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let data = std::fs::read(staged_path)?;
+        write_synced(&new_path, &data)
+            .with_context(|| format!("writing {} failed", new_path.display()))?;
+        std::fs::set_permissions(&new_path, Permissions::from_mode(file.mode()?))?;
+    }
+
+    Ok(())
+}
+
+/// Move the existing file to the backup path and the new file into place
+fn install_file(path: &Path) -> anyhow::Result<InstalledFile> {
+    let new_path = suffixed_path(path, NEW_SUFFIX);
+    let backup_path = suffixed_path(path, BACKUP_SUFFIX);
+
+    let backup = if path.exists() {
+        std::fs::rename(path, &backup_path)
+            .with_context(|| format!("backing up {} failed", path.display()))?;
+        Some(backup_path)
+    } else {
+        None
+    };
+
+    if let Err(error) = std::fs::rename(&new_path, path) {
+        if let Some(backup) = &backup {
+            let _ = std::fs::rename(backup, path);
+        }
+
+        return Err(
+            anyhow::Error::new(error).context(format!("installing {} failed", path.display()))
+        );
+    }
+
+    Ok(InstalledFile {
+        path: path.to_path_buf(),
+        backup,
+    })
+}
+
+/// Restore the replaced files and remove the added ones
+fn undo_install(installed: &[InstalledFile]) {
+    tracing::warn!("rolling back patch");
+
+    for file in installed.iter().rev() {
+        let result = match &file.backup {
+            Some(backup) => std::fs::rename(backup, &file.path),
+            None => std::fs::remove_file(&file.path),
+        };
+
+        if let Err(error) = result {
+            tracing::error!(?error, path = ?file.path, "rollback failed");
+        }
+    }
+}
+
+fn remove_new_files(manifest: &Manifest) {
+    for file in &manifest.files {
+        let _ = std::fs::remove_file(suffixed_path(&file.path, NEW_SUFFIX));
+    }
+}
+
+fn run_command(command: &[String]) -> anyhow::Result<()> {
+    let (program, args) = command.split_first().expect("validated command");
+    let output = crate::logging::log_command_output(Command::new(program).args(args))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "post install command {} exited with {}",
+            command.join(" "),
+            output.status
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::files::sha256_hex;
+
+    /// Serve the files over HTTP on localhost and return the base URL
+    fn spawn_file_server(files: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let (status, body) = match files.iter().find(|(name, _)| *name == path) {
+                    Some((_, data)) => ("200 OK", data.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+
+                let stream = reader.get_mut();
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+
+        format!("http://{address}")
+    }
+
+    /// Returns an empty directory for the test
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    fn manifest_file(url: String, data: &[u8], path: PathBuf) -> ManifestFile {
+        ManifestFile {
+            url,
+            sha256: sha256_hex(data),
+            path,
+            mode: default_mode(),
+        }
+    }
+
+    /// Returns the files in the directory other than the staging directory
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "staging")
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+
+    /// A directory with an existing file `a` and a manifest that replaces
+    /// `a` and adds `b`
+    fn replacing_manifest(name: &str, b_data: &[u8]) -> (PathBuf, Manifest) {
+        let dir = temp_dir(name);
+        std::fs::write(dir.join("a"), "old a").unwrap();
+
+        let url = spawn_file_server(vec![("/a", b"new a".to_vec()), ("/b", b"new b".to_vec())]);
+        let manifest = Manifest {
+            version: 1,
+            files: vec![
+                ManifestFile {
+                    mode: "755".to_string(),
+                    ..manifest_file(format!("{url}/a"), b"new a", dir.join("a"))
+                },
+                manifest_file(format!("{url}/b"), b_data, dir.join("b")),
+            ],
+            post_install: Vec::new(),
+        };
+
+        (dir, manifest)
+    }
+
+    #[test]
+    fn test_validate() {
+        let file = |path: &str| manifest_file(String::new(), b"", PathBuf::from(path));
+        let manifest = |files: Vec<ManifestFile>| Manifest {
+            version: 1,
+            files,
+            post_install: Vec::new(),
+        };
+
+        assert!(manifest(vec![file("/usr/bin/a"), file("/usr/bin/b")])
+            .validate()
+            .is_ok());
+        assert!(manifest(vec![file("usr/bin/a")]).validate().is_err());
+        assert!(manifest(vec![file("/")]).validate().is_err());
+        assert!(manifest(vec![file("/usr/bin/a"), file("/usr/bin/a")])
+            .validate()
+            .is_err());
+        assert!(manifest(vec![ManifestFile {
+            sha256: "abc".to_string(),
+            ..file("/usr/bin/a")
+        }])
+        .validate()
+        .is_err());
+        assert!(manifest(vec![ManifestFile {
+            mode: "999".to_string(),
+            ..file("/usr/bin/a")
+        }])
+        .validate()
+        .is_err());
+        assert!(Manifest {
+            post_install: vec![Vec::new()],
+            ..manifest(Vec::new())
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_install() {
+        let (dir, manifest) = replacing_manifest("install", b"new b");
+
+        install(&manifest, &dir.join("staging"), |_| {}).unwrap();
+
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"new a");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"new b");
+        assert_eq!(
+            std::fs::metadata(dir.join("a"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o755
+        );
+        assert_eq!(file_names(&dir), ["a", "b"]);
+        assert!(!dir.join("staging").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_hash_mismatch() {
+        let (dir, manifest) = replacing_manifest("install-hash-mismatch", b"other b");
+
+        let error = install(&manifest, &dir.join("staging"), |_| {}).unwrap_err();
+
+        assert!(format!("{error:#}").contains("hash of"));
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"old a");
+        assert_eq!(file_names(&dir), ["a"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_post_install_failure() {
+        let (dir, mut manifest) = replacing_manifest("install-post-install", b"new b");
+        manifest.post_install = vec![vec!["false".to_string()]];
+
+        assert!(install(&manifest, &dir.join("staging"), |_| {}).is_err());
+
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"old a");
+        assert_eq!(file_names(&dir), ["a"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_backups() {
+        let (dir, manifest) = replacing_manifest("restore-backups", b"new b");
+        let paths = [dir.join("a")];
+
+        install_keeping_backups(&manifest, &dir.join("staging"), |_| {}).unwrap();
+
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"new a");
+        assert_eq!(
+            std::fs::read(dir.join("a.warrior4-prev")).unwrap(),
+            b"old a"
+        );
+
+        restore_backups(paths.iter().map(PathBuf::as_path)).unwrap();
+
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"old a");
+        assert_eq!(file_names(&dir), ["a", "b"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
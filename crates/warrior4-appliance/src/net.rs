@@ -14,3 +14,16 @@ pub fn get_eth0_ip_address() -> String {
 
     ip_addresses.join(", ")
 }
+
+/// Download the body of the URL into memory
+pub fn download(url: &str) -> anyhow::Result<Vec<u8>> {
+    let response = reqwest::blocking::get(url)?;
+
+    tracing::debug!(url, status_code = %response.status(), "download response");
+
+    if !response.status().is_success() {
+        anyhow::bail!("server responded with {}", response.status());
+    }
+
+    Ok(response.bytes()?.to_vec())
+}
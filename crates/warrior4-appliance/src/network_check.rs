@@ -0,0 +1,309 @@
+//! Report from warrior4-network-check and explanations of failures
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+/// JSON report printed by `warrior4-network-check --format json`
+#[derive(Debug, Deserialize)]
+pub struct NetworkReport {
+    #[serde(default)]
+    pub tests: Vec<NetworkTest>,
+    #[serde(default)]
+    pub families: Vec<FamilyResult>,
+}
+
+/// Results of the tests limited to IPv4 or IPv6
+#[derive(Debug, Deserialize)]
+pub struct FamilyResult {
+    /// `ipv4` or `ipv6`
+    pub family: String,
+    /// Whether the hosts have addresses of this family
+    pub available: bool,
+    pub passed: bool,
+}
+
+impl FamilyResult {
+    fn label(&self) -> &str {
+        match self.family.as_str() {
+            "ipv4" => "IPv4",
+            "ipv6" => "IPv6",
+            family => family,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkTest {
+    pub name: String,
+    /// Whether the test failing fails the whole check
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// `pass`, `fail`, `intercepted`, `error`, or `incomplete`
+    pub outcome: String,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    #[serde(default)]
+    pub status_code: Option<u16>,
+    #[serde(default)]
+    pub error_kind: Option<String>,
+    #[serde(default)]
+    pub interference: Option<Interference>,
+    #[serde(default)]
+    pub dns_findings: Vec<DnsFinding>,
+}
+
+/// How the network modified the HTTP traffic
+#[derive(Debug, Deserialize)]
+pub struct Interference {
+    /// `redirect`, `injected_content`, `proxy_headers`, `content_length_mismatch`, or `tls_interception`
+    pub reason: String,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Issuer of the certificate for `tls_interception`
+    #[serde(default)]
+    pub issuer: Option<String>,
+}
+
+impl Interference {
+    fn explain(&self) -> String {
+        match self.reason.as_str() {
+            "redirect" => format!(
+                "Web pages are redirected{}. This is usually a captive portal login page used by hotel, university, or public networks. Log in to the network with a web browser on the host machine.",
+                self.location
+                    .as_deref()
+                    .map(|location| format!(" to {location}"))
+                    .unwrap_or_default()
+            ),
+            "injected_content" => "Web pages are modified by the network (content is replaced or injected). A captive portal, transparent proxy, or content filter is in use.".to_string(),
+            "proxy_headers" => format!(
+                "Web traffic passes through a transparent proxy ({}). The proxy may modify or cache archived data.",
+                self.headers.join(", ")
+            ),
+            "content_length_mismatch" => "Web pages are altered by the network (the content length was changed). A transparent proxy or content filter is in use.".to_string(),
+            "tls_interception" => format!(
+                "Encrypted connections are intercepted: the server certificate was issued by {} instead of the expected authority. A firewall, antivirus program, or corporate proxy is decrypting HTTPS traffic, so archived data cannot be trusted.",
+                self.issuer.as_deref().filter(|issuer| !issuer.is_empty()).unwrap_or("an unknown issuer")
+            ),
+            _ => "Web traffic is intercepted by the network.".to_string(),
+        }
+    }
+}
+
+/// Difference between the answers of DNS resolvers
+#[derive(Debug, Deserialize)]
+pub struct DnsFinding {
+    /// `nxdomain_rewritten`, `blocked`, `mismatch`, or `no_response`
+    pub finding: String,
+    pub resolver: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    #[serde(default)]
+    pub expected: Vec<IpAddr>,
+}
+
+impl DnsFinding {
+    fn explain(&self) -> String {
+        let name = self.name.as_deref().unwrap_or("a domain");
+
+        match self.finding.as_str() {
+            "nxdomain_rewritten" => format!(
+                "The DNS server {} answers for domains that do not exist{}. It is redirecting DNS lookups.",
+                self.resolver,
+                format_addresses(&self.addresses)
+            ),
+            "blocked" => format!(
+                "The DNS server {} says {name} does not exist while other DNS servers resolve it. The domain may be blocked.",
+                self.resolver
+            ),
+            "mismatch" => format!(
+                "The DNS server {} resolved {name}{} while other DNS servers resolved it{}. DNS lookups may be redirected.",
+                self.resolver,
+                format_addresses(&self.addresses),
+                format_addresses(&self.expected)
+            ),
+            "no_response" => format!(
+                "The DNS server {} did not respond. DNS traffic to it may be blocked by a firewall.",
+                self.resolver
+            ),
+            _ => format!("The DNS server {} gave unexpected answers.", self.resolver),
+        }
+    }
+}
+
+impl NetworkTest {
+    fn is_pass(&self) -> bool {
+        self.outcome == "pass"
+    }
+
+    fn has_error_kind(&self, kinds: &[&str]) -> bool {
+        self.error_kind
+            .as_deref()
+            .is_some_and(|kind| kinds.contains(&kind))
+    }
+}
+
+impl NetworkReport {
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    fn test(&self, name: &str) -> Option<&NetworkTest> {
+        self.tests.iter().find(|test| test.name == name)
+    }
+
+    /// Returns warnings about problems that don't fail the check
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        warnings.extend(self.family_fallback());
+
+        for test in &self.tests {
+            if !test.required && !test.is_pass() {
+                warnings.push(format!(
+                    "The {} check failed: {}",
+                    test.name,
+                    test.message.as_deref().unwrap_or(&test.outcome)
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    /// Returns a warning if connections only work over one address family
+    fn family_fallback(&self) -> Option<String> {
+        let broken = self
+            .families
+            .iter()
+            .find(|family| family.available && !family.passed)?;
+        let working = self.families.iter().find(|family| family.passed)?;
+
+        Some(format!(
+            "{} is broken, falling back to {}. Downloads may stall or be slow. Check the {} settings of the network or disable {} on the host machine.",
+            broken.label(),
+            working.label(),
+            broken.label(),
+            broken.label()
+        ))
+    }
+
+    /// Returns user friendly explanations of the likely causes of the failed tests
+    pub fn explain(&self) -> Vec<String> {
+        let mut explanations = Vec::new();
+        let failed = self
+            .tests
+            .iter()
+            .filter(|test| test.required && !test.is_pass())
+            .collect::<Vec<_>>();
+
+        if failed.is_empty() {
+            return explanations;
+        }
+
+        if failed.iter().all(|test| {
+            test.has_error_kind(&["timeout", "connection_failed", "connection_refused"])
+        }) {
+            explanations.push(
+                "The internet cannot be reached. Check the network connection of the host machine and the virtual machine's network settings."
+                    .to_string(),
+            );
+            return explanations;
+        }
+
+        if let Some(test) = self
+            .test("nonexistent")
+            .filter(|test| test.outcome == "fail")
+        {
+            explanations.push(format!(
+                "DNS is hijacked: a domain name that does not exist was resolved{}. Your network or internet provider is redirecting DNS lookups.",
+                format_addresses(&test.addresses)
+            ));
+        }
+
+        for finding in self.tests.iter().flat_map(|test| &test.dns_findings) {
+            let explanation = finding.explain();
+
+            if !explanations.contains(&explanation) {
+                explanations.push(explanation);
+            }
+        }
+
+        if failed
+            .iter()
+            .any(|test| test.has_error_kind(&["host_not_found"]))
+        {
+            explanations.push(
+                "DNS lookups are failing. Check the DNS settings of the network.".to_string(),
+            );
+        }
+
+        let target_passed = self.test("target").is_some_and(|test| test.is_pass());
+
+        for test in &failed {
+            if let Some(interference) = &test.interference {
+                let explanation = interference.explain();
+
+                if !explanations.contains(&explanation) {
+                    explanations.push(explanation);
+                }
+            }
+        }
+
+        if let Some(test) = self.test("cleartext").filter(|test| test.outcome == "fail") {
+            if target_passed || test.status_code.is_some() {
+                explanations.push(
+                    "HTTP is intercepted, possibly by a captive portal or transparent proxy. If the network requires logging in, do so with a web browser on the host machine."
+                        .to_string(),
+                );
+            }
+        }
+
+        if let Some(test) = self.test("target").filter(|test| !test.is_pass()) {
+            if test.has_error_kind(&["tls"]) {
+                explanations.push(
+                    "HTTPS connections are intercepted or blocked. A firewall or antivirus program may be inspecting encrypted connections."
+                        .to_string(),
+                );
+            }
+        }
+
+        if let Some(warning) = self.family_fallback() {
+            explanations.push(warning);
+        }
+
+        if explanations.is_empty() {
+            for test in failed {
+                explanations.push(format!(
+                    "The {} check failed: {}",
+                    test.name,
+                    test.message.as_deref().unwrap_or(&test.outcome)
+                ));
+            }
+        }
+
+        explanations
+    }
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn format_addresses(addresses: &[IpAddr]) -> String {
+    if addresses.is_empty() {
+        String::new()
+    } else {
+        let addresses = addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>();
+        format!(" to {}", addresses.join(", "))
+    }
+}
@@ -0,0 +1,226 @@
+//! Patch file versions and the patch history in the state file
+
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{files::sha256_hex, manifest::Manifest, state::State};
+
+/// Directory where the files of a patch manifest are downloaded to
+pub const STAGING_DIR: &str = "/var/lib/warrior4-appliance/patch-staging";
+
+/// Path of the file holding the version of the patch built into the image
+/// or applied last
+pub const PATCH_VERSION_PATH: &str = "/var/lib/warrior4-appliance/patch-version";
+
+/// Comment line in the patch file carrying its version, such as
+/// `# warrior4-patch-version: 3`
+const VERSION_HEADER: &str = "# warrior4-patch-version:";
+
+/// Number of header lines searched for the version
+const MAX_HEADER_LINES: usize = 20;
+
+/// A downloaded patch script or manifest
+pub struct PatchFile {
+    pub data: Vec<u8>,
+    /// Version from the header or `None` if the patch is unversioned
+    pub version: Option<u64>,
+    /// Hex SHA-256 hash of the data
+    pub sha256: String,
+    /// The parsed manifest or `None` if the patch is a script
+    pub manifest: Option<Manifest>,
+}
+
+impl PatchFile {
+    /// Parse a patch script
+    pub fn new(data: Vec<u8>) -> anyhow::Result<Self> {
+        let version = parse_version(&data)?;
+        let sha256 = sha256_hex(&data);
+
+        Ok(Self {
+            data,
+            version,
+            sha256,
+            manifest: None,
+        })
+    }
+
+    /// Parse a patch manifest
+    pub fn from_manifest(data: Vec<u8>) -> anyhow::Result<Self> {
+        let manifest = Manifest::parse(&data)?;
+        let sha256 = sha256_hex(&data);
+
+        Ok(Self {
+            data,
+            version: Some(manifest.version),
+            sha256,
+            manifest: Some(manifest),
+        })
+    }
+
+    /// Returns whether the patch was applied before
+    ///
+    /// A versioned patch is applied if its version is not newer than the
+    /// installed version. An unversioned patch is applied if a patch with
+    /// the same hash was applied.
+    pub fn is_applied(&self, installed_version: u64, state: &State) -> bool {
+        match self.version {
+            Some(version) => version <= installed_version,
+            None => state.patch_hash_applied(&self.sha256),
+        }
+    }
+}
+
+/// Returns the version in the patch file's header
+fn parse_version(data: &[u8]) -> anyhow::Result<Option<u64>> {
+    let text = String::from_utf8_lossy(data);
+
+    for line in text.lines().take(MAX_HEADER_LINES) {
+        if let Some(value) = line.strip_prefix(VERSION_HEADER) {
+            let value = value.trim();
+            let version = value
+                .parse()
+                .map_err(|error| anyhow::anyhow!("invalid patch version {value:?}: {error}"))?;
+
+            return Ok(Some(version));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read the version in the patch version file (0 if the file does not exist)
+pub fn read_installed_version(path: &Path) -> anyhow::Result<u64> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text.trim().parse()?),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Write the version to the patch version file
+pub fn write_installed_version(path: &Path, version: u64) -> anyhow::Result<()> {
+    std::fs::write(path, format!("{version}\n"))?;
+
+    Ok(())
+}
+
+/// An entry of the patch history in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchRecord {
+    pub boot: u64,
+    pub version: Option<u64>,
+    pub sha256: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub outcome: PatchOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum PatchOutcome {
+    /// The patch is being run, or the machine restarted while it was running
+    Running,
+    Applied,
+    Failed {
+        error: String,
+    },
+    /// The signature was not valid so the patch was not run
+    Refused {
+        error: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(sha256: &str, outcome: PatchOutcome) -> PatchRecord {
+        PatchRecord {
+            boot: 1,
+            version: None,
+            sha256: sha256.to_string(),
+            started: Utc::now(),
+            finished: Some(Utc::now()),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version(b"#!/bin/sh\necho hello\n").unwrap(), None);
+        assert_eq!(parse_version(b"").unwrap(), None);
+        assert_eq!(
+            parse_version(b"#!/bin/sh\n# warrior4-patch-version: 3\n").unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            parse_version(b"#!/bin/sh\n# warrior4-patch-version:12 \r\n").unwrap(),
+            Some(12)
+        );
+
+        assert!(parse_version(b"#!/bin/sh\n# warrior4-patch-version: three\n").is_err());
+        assert!(parse_version(b"#!/bin/sh\n# warrior4-patch-version:\n").is_err());
+        assert!(parse_version(b"#!/bin/sh\n# warrior4-patch-version: -1\n").is_err());
+    }
+
+    #[test]
+    fn test_version_outside_header() {
+        let mut data = "#!/bin/sh\n".repeat(MAX_HEADER_LINES);
+        data.push_str("# warrior4-patch-version: 3\n");
+
+        assert_eq!(parse_version(data.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_versioned_patch_applied() {
+        let state = State::new();
+        let patch = PatchFile::new(b"#!/bin/sh\n# warrior4-patch-version: 3\n".to_vec()).unwrap();
+
+        assert!(!patch.is_applied(0, &state));
+        assert!(!patch.is_applied(2, &state));
+        assert!(patch.is_applied(3, &state));
+        assert!(patch.is_applied(4, &state));
+    }
+
+    #[test]
+    fn test_unversioned_patch_applied() {
+        let patch = PatchFile::new(b"#!/bin/sh\necho hello\n".to_vec()).unwrap();
+        let mut state = State::new();
+
+        assert!(!patch.is_applied(0, &state));
+        assert!(!patch.is_applied(100, &state));
+
+        state.record_patch(record(
+            &patch.sha256,
+            PatchOutcome::Failed {
+                error: "exit status 1".to_string(),
+            },
+        ));
+        state.record_patch(record("other", PatchOutcome::Applied));
+        assert!(!patch.is_applied(0, &state));
+
+        state.record_patch(record(&patch.sha256, PatchOutcome::Applied));
+        assert!(patch.is_applied(0, &state));
+    }
+
+    #[test]
+    fn test_installed_version_file() {
+        let path = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-patch-version",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(read_installed_version(&path).unwrap(), 0);
+        write_installed_version(&path, 7).unwrap();
+        assert_eq!(read_installed_version(&path).unwrap(), 7);
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(read_installed_version(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
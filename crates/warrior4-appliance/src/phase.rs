@@ -0,0 +1,259 @@
+//! Phases of the manager's boot state machine
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A named step of starting up and running the appliance, in execution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    LoadState,
+    WaitForDocker,
+    CheckConnectivity,
+    Patch,
+    SelfUpdate,
+    CreateContainers,
+    UpdateContainers,
+    StartContainers,
+    WaitForPayload,
+    Monitor,
+}
+
+impl Phase {
+    pub const FIRST: Phase = Phase::LoadState;
+
+    /// Returns the phase that follows after this phase completes
+    pub fn next(self) -> Option<Phase> {
+        match self {
+            Phase::LoadState => Some(Phase::WaitForDocker),
+            Phase::WaitForDocker => Some(Phase::CheckConnectivity),
+            Phase::CheckConnectivity => Some(Phase::Patch),
+            Phase::Patch => Some(Phase::SelfUpdate),
+            Phase::SelfUpdate => Some(Phase::CreateContainers),
+            Phase::CreateContainers => Some(Phase::UpdateContainers),
+            Phase::UpdateContainers => Some(Phase::StartContainers),
+            Phase::StartContainers => Some(Phase::WaitForPayload),
+            Phase::WaitForPayload => Some(Phase::Monitor),
+            Phase::Monitor => None,
+        }
+    }
+
+    /// Human readable name used in messages
+    pub fn description(self) -> &'static str {
+        match self {
+            Phase::LoadState => "Loading the system state",
+            Phase::WaitForDocker => "Waiting for Docker",
+            Phase::CheckConnectivity => "Checking internet connectivity",
+            Phase::Patch => "Patching the system",
+            Phase::SelfUpdate => "Updating the appliance software",
+            Phase::CreateContainers => "Creating the containers",
+            Phase::UpdateContainers => "Updating the containers",
+            Phase::StartContainers => "Starting the containers",
+            Phase::WaitForPayload => "Starting the web interface",
+            Phase::Monitor => "Monitoring the containers",
+        }
+    }
+
+    pub fn policy(self) -> PhasePolicy {
+        match self {
+            Phase::LoadState => PhasePolicy {
+                max_attempts: 3,
+                retry_delay: Duration::from_secs(5),
+                max_retry_delay: Duration::from_secs(5),
+                retry_time_limit: Some(Duration::from_secs(60)),
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+            // Each attempt polls Docker until it is ready or the poll times out
+            Phase::WaitForDocker => PhasePolicy {
+                max_attempts: 2,
+                retry_delay: Duration::from_secs(5),
+                max_retry_delay: Duration::from_secs(5),
+                retry_time_limit: Some(Duration::from_secs(60 * 30)),
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+            Phase::CheckConnectivity => PhasePolicy {
+                max_attempts: 60,
+                retry_delay: Duration::from_secs(60),
+                max_retry_delay: Duration::from_secs(60 * 30),
+                retry_time_limit: Some(Duration::from_secs(60 * 60 * 24)),
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+            Phase::Patch | Phase::SelfUpdate => PhasePolicy {
+                max_attempts: 2,
+                retry_delay: Duration::from_secs(30),
+                max_retry_delay: Duration::from_secs(30),
+                retry_time_limit: Some(Duration::from_secs(60 * 30)),
+                on_failure: FailureAction::Skip,
+                skip_if_passed_within: Some(Duration::from_secs(60 * 60)),
+            },
+            Phase::CreateContainers => PhasePolicy {
+                max_attempts: 5,
+                retry_delay: Duration::from_secs(60),
+                max_retry_delay: Duration::from_secs(60 * 15),
+                retry_time_limit: Some(Duration::from_secs(60 * 60 * 2)),
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+            Phase::UpdateContainers => PhasePolicy {
+                max_attempts: 2,
+                retry_delay: Duration::from_secs(30),
+                max_retry_delay: Duration::from_secs(30),
+                retry_time_limit: Some(Duration::from_secs(60 * 60)),
+                on_failure: FailureAction::Skip,
+                skip_if_passed_within: Some(Duration::from_secs(60 * 60)),
+            },
+            Phase::StartContainers | Phase::WaitForPayload => PhasePolicy {
+                max_attempts: 3,
+                retry_delay: Duration::from_secs(60),
+                max_retry_delay: Duration::from_secs(60 * 5),
+                retry_time_limit: Some(Duration::from_secs(60 * 60)),
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+            Phase::Monitor => PhasePolicy {
+                max_attempts: 10,
+                retry_delay: Duration::from_secs(60),
+                max_retry_delay: Duration::from_secs(60 * 60 * 4),
+                retry_time_limit: None,
+                on_failure: FailureAction::Reboot,
+                skip_if_passed_within: None,
+            },
+        }
+    }
+}
+
+/// How a phase is retried and what happens when it keeps failing
+#[derive(Debug, Clone, Copy)]
+pub struct PhasePolicy {
+    pub max_attempts: u32,
+    /// Delay before the first retry. It doubles for each retry after that.
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    /// No attempt is started once this much time has passed since the first
+    /// attempt started. A running attempt is not interrupted.
+    pub retry_time_limit: Option<Duration>,
+    pub on_failure: FailureAction,
+    /// Skip the phase if it passed within this duration (including a previous boot)
+    pub skip_if_passed_within: Option<Duration>,
+}
+
+impl PhasePolicy {
+    /// Returns whether the phase is skipped because it last passed within
+    /// `skip_if_passed_within` of now
+    pub fn skips_after_pass(&self, last_passed: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let Some(window) = self.skip_if_passed_within else {
+            return false;
+        };
+        let elapsed = (now - last_passed).to_std().unwrap_or_default();
+
+        elapsed < window
+    }
+
+    /// Returns the delay before the given attempt number (starting at 2)
+    pub fn delay_before(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(2));
+
+        self.retry_delay
+            .saturating_mul(factor)
+            .min(self.max_retry_delay)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureAction {
+    /// Show the error and restart the machine
+    Reboot,
+    /// Show a warning and continue to the next phase
+    Skip,
+}
+
+/// An entry of the phase history in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhaseRecord {
+    pub boot: u64,
+    pub phase: Phase,
+    pub attempt: u32,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    #[serde(flatten)]
+    pub outcome: PhaseOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum PhaseOutcome {
+    Passed,
+    Failed { error: String },
+    Skipped { reason: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns every phase in execution order
+    fn all_phases() -> Vec<Phase> {
+        std::iter::successors(Some(Phase::FIRST), |phase| phase.next()).collect()
+    }
+
+    #[test]
+    fn test_phase_order() {
+        let phases = all_phases();
+
+        assert_eq!(phases.len(), 10);
+        assert_eq!(phases.last(), Some(&Phase::Monitor));
+        assert!(phases.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_failure_actions() {
+        let skipped = all_phases()
+            .into_iter()
+            .filter(|phase| phase.policy().on_failure == FailureAction::Skip)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            skipped,
+            [Phase::Patch, Phase::SelfUpdate, Phase::UpdateContainers]
+        );
+    }
+
+    #[test]
+    fn test_skip_if_passed_within() {
+        let now = Utc::now();
+        let minutes_ago = |minutes| now - chrono::Duration::minutes(minutes);
+
+        for phase in [Phase::Patch, Phase::SelfUpdate, Phase::UpdateContainers] {
+            let policy = phase.policy();
+
+            assert!(policy.skips_after_pass(minutes_ago(10), now));
+            assert!(!policy.skips_after_pass(minutes_ago(61), now));
+        }
+
+        // Phases that must run on every boot are never skipped
+        for phase in [Phase::LoadState, Phase::CreateContainers, Phase::Monitor] {
+            assert!(!phase.policy().skips_after_pass(minutes_ago(1), now));
+        }
+
+        // A pass time in the future, such as after a clock change, counts as recent
+        assert!(Phase::Patch
+            .policy()
+            .skips_after_pass(now + chrono::Duration::minutes(5), now));
+    }
+
+    #[test]
+    fn test_delay_before() {
+        let policy = Phase::CreateContainers.policy();
+
+        assert_eq!(policy.delay_before(2), Duration::from_secs(60));
+        assert_eq!(policy.delay_before(3), Duration::from_secs(120));
+        assert_eq!(policy.delay_before(4), Duration::from_secs(240));
+        assert_eq!(policy.delay_before(10), Duration::from_secs(60 * 15));
+        assert_eq!(policy.delay_before(100), Duration::from_secs(60 * 15));
+    }
+}
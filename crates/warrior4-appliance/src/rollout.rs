@@ -0,0 +1,80 @@
+//! Staged rollout of patches and updates to a share of the machines
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Rollout file published by a release channel
+#[derive(Debug, Deserialize)]
+struct Rollout {
+    /// Share of machines from 0 to 100 that install the channel's patches and updates
+    percent: u8,
+}
+
+/// Returns the machine's rollout bucket from 0 to 99
+///
+/// The bucket only depends on the state's UUID, so a machine that is in a
+/// rollout stays in it while the percentage increases.
+pub fn bucket(uuid: &Uuid) -> u8 {
+    let hash = Sha256::digest(uuid.as_bytes());
+    let value = u64::from_be_bytes(hash[..8].try_into().expect("hash is 32 bytes"));
+
+    (value % 100) as u8
+}
+
+/// Returns whether the machine installs a channel rolled out to the percentage of machines
+pub fn includes(uuid: &Uuid, percent: u8) -> bool {
+    bucket(uuid) < percent
+}
+
+/// Download the channel's rollout percentage (100 if the channel has no rollout file)
+pub fn fetch_percent(url: Option<&str>) -> anyhow::Result<u8> {
+    let Some(url) = url else {
+        return Ok(100);
+    };
+
+    let data = crate::net::download(url)?;
+
+    parse_percent(&data)
+}
+
+fn parse_percent(data: &[u8]) -> anyhow::Result<u8> {
+    let rollout = serde_json::from_slice::<Rollout>(data)?;
+
+    Ok(rollout.percent.min(100))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let uuid = Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert_eq!(bucket(&uuid), bucket(&uuid));
+
+        for _ in 0..1000 {
+            assert!(bucket(&Uuid::new_v4()) < 100);
+        }
+    }
+
+    #[test]
+    fn test_includes() {
+        for _ in 0..1000 {
+            let uuid = Uuid::new_v4();
+            assert!(!includes(&uuid, 0));
+            assert!(includes(&uuid, 100));
+            assert_eq!(includes(&uuid, 50), bucket(&uuid) < 50);
+        }
+    }
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(fetch_percent(None).unwrap(), 100);
+        assert_eq!(parse_percent(br#"{"percent": 0}"#).unwrap(), 0);
+        assert_eq!(parse_percent(br#"{"percent": 25}"#).unwrap(), 25);
+        assert_eq!(parse_percent(br#"{"percent": 200}"#).unwrap(), 100);
+        assert!(parse_percent(br#"{"percent": -1}"#).is_err());
+        assert!(parse_percent(b"{}").is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! Self-update of the appliance binaries with rollback
+//!
+//! The release file lists the binaries of a version:
+//!
+//! ```json
+//! {
+//!     "version": "4.2",
+//!     "binaries": [
+//!         {
+//!             "url": "https://example.org/warrior4-appliance",
+//!             "sha256": "<hex>",
+//!             "path": "/usr/bin/warrior4-appliance",
+//!             "service": "warrior4-appliance"
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! The binaries are installed like the files of a patch manifest, but the
+//! replaced binaries are kept as `<name>.warrior4-prev`. The update stays
+//! pending in the state file until the payload is ready with the new manager
+//! binary running and the other restarted services started. If the manager
+//! starts a second time while the update is pending, the previous binaries
+//! are restored.
+use std::{
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    files::{sha256_hex, suffixed_path},
+    manifest::{self, Manifest, ManifestFile},
+    state::State,
+};
+
+/// OpenRC service of the manager itself
+pub const MANAGER_SERVICE: &str = "warrior4-appliance";
+
+/// Number of manager starts allowed for a pending update to reach payload ready
+const MAX_PENDING_STARTS: u32 = 1;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub version: String,
+    pub binaries: Vec<ReleaseBinary>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseBinary {
+    pub url: String,
+    /// Hex SHA-256 hash of the binary
+    pub sha256: String,
+    /// Absolute path where the binary is installed
+    pub path: PathBuf,
+    /// OpenRC service restarted to run the new binary
+    pub service: String,
+}
+
+impl Release {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let release = serde_json::from_slice::<Release>(data).context("invalid release file")?;
+        to_manifest(&release.binaries.iter().collect::<Vec<_>>()).validate()?;
+
+        Ok(release)
+    }
+
+    /// Returns the binaries whose installed file is different
+    pub fn outdated_binaries(&self) -> Vec<&ReleaseBinary> {
+        self.binaries
+            .iter()
+            .filter(|binary| match std::fs::read(&binary.path) {
+                Ok(data) => !sha256_hex(&data).eq_ignore_ascii_case(&binary.sha256),
+                Err(_) => true,
+            })
+            .collect()
+    }
+}
+
+/// An entry of the self-update history in the state file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfUpdateRecord {
+    pub boot: u64,
+    pub version: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Paths of the replaced binaries
+    pub paths: Vec<PathBuf>,
+    /// Services restarted to run the new binaries
+    pub services: Vec<String>,
+    /// Number of times the manager started while the update was pending
+    #[serde(default)]
+    pub starts: u32,
+    /// Hex SHA-256 hash of the manager binary that must be running to finish the update
+    #[serde(default)]
+    pub manager_sha256: Option<String>,
+    #[serde(flatten)]
+    pub outcome: SelfUpdateOutcome,
+}
+
+impl SelfUpdateRecord {
+    /// Returns whether the running manager binary is the one the update expects
+    ///
+    /// Updates recorded before the expected hash was stored match any binary.
+    pub fn is_running(&self, running_sha256: &str) -> bool {
+        self.manager_sha256
+            .as_deref()
+            .is_none_or(|sha256| sha256.eq_ignore_ascii_case(running_sha256))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SelfUpdateOutcome {
+    /// Installed but the payload has not been ready with the new binaries yet
+    Pending,
+    Succeeded,
+    Failed {
+        error: String,
+    },
+    RolledBack {
+        reason: String,
+    },
+}
+
+/// Download and verify the binaries, then install them keeping the previous binaries
+pub fn install<P>(
+    binaries: &[&ReleaseBinary],
+    staging_dir: &Path,
+    progress: P,
+) -> anyhow::Result<()>
+where
+    P: FnMut(String),
+{
+    manifest::install_keeping_backups(&to_manifest(binaries), staging_dir, progress)
+}
+
+/// Returns a manifest that installs the binaries as executables
+fn to_manifest(binaries: &[&ReleaseBinary]) -> Manifest {
+    Manifest {
+        // Only patches are versioned
+        version: 0,
+        files: binaries
+            .iter()
+            .map(|binary| ManifestFile {
+                url: binary.url.clone(),
+                sha256: binary.sha256.clone(),
+                path: binary.path.clone(),
+                mode: "755".to_string(),
+            })
+            .collect(),
+        post_install: Vec::new(),
+    }
+}
+
+/// Returns the hex SHA-256 hash of the running manager binary
+///
+/// The binary is read through `/proc/self/exe` so it is the running one
+/// even if the file was replaced since the process started.
+pub fn running_manager_sha256() -> anyhow::Result<String> {
+    let data = std::fs::read("/proc/self/exe").context("reading the running binary failed")?;
+
+    Ok(sha256_hex(&data))
+}
+
+/// Check that the restarted services other than the manager are running
+pub fn check_services(services: &[String]) -> anyhow::Result<()> {
+    for service in services {
+        if service != MANAGER_SERVICE {
+            let output = crate::logging::log_command_output(
+                Command::new("rc-service").arg(service).arg("status"),
+            )?;
+
+            if !output.status.success() {
+                anyhow::bail!("the {service} service is not running");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Restore the previous binaries of the update and record the outcome
+///
+/// The update is recorded as failed instead if the binaries could not be restored.
+pub fn roll_back(record: &mut SelfUpdateRecord, outcome: SelfUpdateOutcome) -> anyhow::Result<()> {
+    let result = manifest::restore_backups(record.paths.iter().map(PathBuf::as_path));
+
+    record.finished = Some(Utc::now());
+    record.outcome = match &result {
+        Ok(_) => outcome,
+        Err(error) => SelfUpdateOutcome::Failed {
+            error: format!("rollback failed: {error:#}"),
+        },
+    };
+
+    result
+}
+
+/// Restart the services other than the manager
+///
+/// The manager restarts itself with [`exec_self`].
+pub fn restart_services(services: &[String]) -> anyhow::Result<()> {
+    for service in services {
+        if service != MANAGER_SERVICE {
+            tracing::info!(service, "restarting service");
+            crate::logging::log_command_output(
+                Command::new("rc-service").arg(service).arg("restart"),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Count a start of the manager while an update is pending and roll the
+/// update back if the previous start did not get the payload ready
+///
+/// An update whose previous binaries were already restored by the start
+/// script is recorded as rolled back.
+///
+/// Returns true if the binaries were rolled back and the manager should be
+/// started again from its restored binary.
+pub fn check_pending(state_path: &Path) -> anyhow::Result<bool> {
+    if !state_path.try_exists()? {
+        return Ok(false);
+    }
+
+    let mut state = State::load(state_path)?;
+
+    let Some(record) = state.pending_self_update() else {
+        return Ok(false);
+    };
+
+    // The start script restores the previous binaries if the new manager
+    // failed before it could roll back by itself
+    let restored = !record
+        .paths
+        .iter()
+        .any(|path| suffixed_path(path, manifest::BACKUP_SUFFIX).exists());
+
+    record.starts += 1;
+
+    if record.starts <= MAX_PENDING_STARTS && !restored {
+        tracing::info!(version = record.version, "self-update is pending");
+        state.save(state_path)?;
+        return Ok(false);
+    }
+
+    let result = if restored {
+        tracing::warn!(
+            version = record.version,
+            "self-update was rolled back by the start script"
+        );
+        record.finished = Some(Utc::now());
+        record.outcome = SelfUpdateOutcome::RolledBack {
+            reason: "the start script restored the previous binaries".to_string(),
+        };
+        Ok(())
+    } else {
+        tracing::warn!(
+            version = record.version,
+            "self-update did not get ready, rolling back"
+        );
+        roll_back(
+            record,
+            SelfUpdateOutcome::RolledBack {
+                reason: "the warrior was not ready before the manager started again".to_string(),
+            },
+        )
+    };
+    let services = record.services.clone();
+    state.save(state_path)?;
+    result?;
+
+    if let Err(error) = restart_services(&services) {
+        tracing::error!(?error, "restarting services after rollback failed");
+    }
+
+    // The running binary is already the previous one if the start script restored it
+    Ok(!restored)
+}
+
+/// Replace the current process with the installed manager binary
+///
+/// The process ID stays the same, so supervise-daemon keeps tracking the manager.
+pub fn exec_self() -> anyhow::Error {
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_default();
+
+    tracing::info!(?program, "restarting the manager");
+
+    let error = Command::new(program).args(args).exec();
+
+    anyhow::Error::new(error).context("restarting the manager failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state file with a pending update that replaced the binary `app` in
+    /// the returned directory
+    fn pending_update(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("app"), "new").unwrap();
+        std::fs::write(dir.join("app.warrior4-prev"), "old").unwrap();
+
+        let mut state = State::new();
+        state.record_self_update(SelfUpdateRecord {
+            boot: 0,
+            version: "4.2".to_string(),
+            started: Utc::now(),
+            finished: None,
+            paths: vec![dir.join("app")],
+            services: Vec::new(),
+            starts: 0,
+            manager_sha256: None,
+            outcome: SelfUpdateOutcome::Pending,
+        });
+        let state_path = dir.join("state.json");
+        state.save(&state_path).unwrap();
+
+        (dir, state_path)
+    }
+
+    fn last_record(state_path: &Path) -> SelfUpdateRecord {
+        State::load(state_path)
+            .unwrap()
+            .self_update_history
+            .pop()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_pending_without_state() {
+        let path = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-missing-state.json",
+            std::process::id()
+        ));
+
+        assert!(!check_pending(&path).unwrap());
+    }
+
+    #[test]
+    fn test_check_pending_rolls_back_on_second_start() {
+        let (dir, state_path) = pending_update("check-pending-second-start");
+
+        assert!(!check_pending(&state_path).unwrap());
+
+        let record = last_record(&state_path);
+        assert_eq!(record.starts, 1);
+        assert_eq!(record.outcome, SelfUpdateOutcome::Pending);
+        assert_eq!(std::fs::read(dir.join("app")).unwrap(), b"new");
+
+        assert!(check_pending(&state_path).unwrap());
+
+        let record = last_record(&state_path);
+        assert!(matches!(
+            record.outcome,
+            SelfUpdateOutcome::RolledBack { .. }
+        ));
+        assert!(record.finished.is_some());
+        assert_eq!(std::fs::read(dir.join("app")).unwrap(), b"old");
+        assert!(!dir.join("app.warrior4-prev").exists());
+
+        assert!(!check_pending(&state_path).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_pending_restored_by_start_script() {
+        let (dir, state_path) = pending_update("check-pending-start-script");
+        std::fs::rename(dir.join("app.warrior4-prev"), dir.join("app")).unwrap();
+
+        assert!(!check_pending(&state_path).unwrap());
+
+        match last_record(&state_path).outcome {
+            SelfUpdateOutcome::RolledBack { reason } => assert!(reason.contains("start script")),
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        }
+        assert_eq!(std::fs::read(dir.join("app")).unwrap(), b"old");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_running() {
+        let (dir, state_path) = pending_update("is-running");
+        let mut record = last_record(&state_path);
+        let running = sha256_hex(b"new");
+
+        assert!(record.is_running(&running));
+
+        record.manager_sha256 = Some(running.to_uppercase());
+        assert!(record.is_running(&running));
+        assert!(!record.is_running(&sha256_hex(b"old")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_roll_back() {
+        let (dir, state_path) = pending_update("roll-back");
+        let mut record = last_record(&state_path);
+        let outcome = SelfUpdateOutcome::Failed {
+            error: "restarting the manager failed".to_string(),
+        };
+
+        roll_back(&mut record, outcome.clone()).unwrap();
+
+        assert_eq!(record.outcome, outcome);
+        assert!(record.finished.is_some());
+        assert_eq!(std::fs::read(dir.join("app")).unwrap(), b"old");
+        assert!(!dir.join("app.warrior4-prev").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! Verification of minisign signatures of downloaded files
+
+use minisign_verify::{PublicKey, Signature};
+
+/// Check the minisign signature of the data against the trusted public keys
+///
+/// The public keys are the base64 encoded second line of a minisign `.pub` file.
+pub fn verify(data: &[u8], signature: &str, public_keys: &[String]) -> anyhow::Result<()> {
+    if public_keys.is_empty() {
+        anyhow::bail!("no public keys are configured to verify the signature");
+    }
+
+    let signature = Signature::decode(signature)
+        .map_err(|error| anyhow::anyhow!("invalid signature file: {error}"))?;
+    let mut last_error = None;
+
+    for public_key in public_keys {
+        let public_key = match PublicKey::from_base64(public_key.trim()) {
+            Ok(public_key) => public_key,
+            Err(error) => {
+                tracing::warn!(%error, public_key, "invalid public key");
+                continue;
+            }
+        };
+
+        match public_key.verify(data, &signature, false) {
+            Ok(_) => return Ok(()),
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    match last_error {
+        Some(error) => anyhow::bail!("signature is not valid: {error}"),
+        None => anyhow::bail!("none of the configured public keys are valid"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Key pair and signature of "test" from the minisign-verify crate's tests
+    const PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==
+";
+    // The same key ID with different key bytes
+    const OTHER_PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO4";
+
+    #[test]
+    fn test_valid_signature() {
+        verify(b"test", SIGNATURE, &[PUBLIC_KEY.to_string()]).unwrap();
+        verify(
+            b"test",
+            SIGNATURE,
+            &["not a key".to_string(), format!(" {PUBLIC_KEY}\n")],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        assert!(verify(b"test", SIGNATURE, &[OTHER_PUBLIC_KEY.to_string()]).is_err());
+        assert!(verify(b"test", SIGNATURE, &["not a key".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_modified_data() {
+        assert!(verify(b"Test", SIGNATURE, &[PUBLIC_KEY.to_string()]).is_err());
+        assert!(verify(b"test\n", SIGNATURE, &[PUBLIC_KEY.to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_malformed_signature() {
+        let keys = [PUBLIC_KEY.to_string()];
+        let truncated = SIGNATURE.lines().take(2).collect::<Vec<_>>().join("\n");
+        let modified = SIGNATURE.replace("timestamp:1556193335", "timestamp:1556193336");
+
+        assert!(verify(b"test", "", &keys).is_err());
+        assert!(verify(b"test", "not a signature", &keys).is_err());
+        assert!(verify(b"test", &truncated, &keys).is_err());
+        assert!(verify(b"test", &modified, &keys).is_err());
+    }
+
+    #[test]
+    fn test_no_keys() {
+        assert!(verify(b"test", SIGNATURE, &[]).is_err());
+    }
+}
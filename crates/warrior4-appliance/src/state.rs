@@ -1,17 +1,45 @@
 //! Data serialized to disk for state management
 
-use std::path::Path;
+use std::{collections::BTreeMap, fs::File, io::Write, path::Path};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+use crate::{
+    patch::{PatchOutcome, PatchRecord},
+    phase::{Phase, PhaseOutcome, PhaseRecord},
+    self_update::{SelfUpdateOutcome, SelfUpdateRecord},
+};
+
+const MAX_PHASE_HISTORY: usize = 200;
+const MAX_PATCH_HISTORY: usize = 50;
+const MAX_SELF_UPDATE_HISTORY: usize = 20;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct State {
+    /// Random ID of the machine (generated when loading a state without one)
     pub uuid: Uuid,
     pub created: DateTime<Utc>,
     pub last_forced_reboot: DateTime<Utc>,
+    /// Container name to the spec hash of the declaration it was created from
+    pub container_spec_hashes: BTreeMap<String, String>,
+    /// Number of times the manager has started with this state
+    pub boot_count: u64,
+    /// The phase the manager is currently in (or was in when it stopped)
+    pub phase: Option<Phase>,
+    /// Most recent phase attempts, oldest first
+    pub phase_history: Vec<PhaseRecord>,
+    pub phase_last_passed: BTreeMap<Phase, DateTime<Utc>>,
+    /// Version of the last versioned patch that was applied
+    pub patch_version: Option<u64>,
+    /// Most recent patch runs, oldest first
+    pub patch_history: Vec<PatchRecord>,
+    /// Release channel selected from the display (the config's default if not set)
+    pub channel: Option<String>,
+    /// Most recent self-updates of the binaries, oldest first
+    pub self_update_history: Vec<SelfUpdateRecord>,
 }
 
 impl State {
@@ -20,12 +48,88 @@ impl State {
             uuid: uuid::Uuid::new_v4(),
             created: Utc::now(),
             last_forced_reboot: Default::default(),
+            container_spec_hashes: Default::default(),
+            boot_count: 0,
+            phase: None,
+            phase_history: Vec::new(),
+            phase_last_passed: Default::default(),
+            patch_version: None,
+            patch_history: Vec::new(),
+            channel: None,
+            self_update_history: Vec::new(),
+        }
+    }
+
+    /// Add a phase attempt to the history
+    pub fn record_phase(&mut self, record: PhaseRecord) {
+        if let PhaseOutcome::Passed = record.outcome {
+            self.phase_last_passed.insert(record.phase, record.finished);
+        }
+
+        self.phase_history.push(record);
+
+        if self.phase_history.len() > MAX_PHASE_HISTORY {
+            let excess = self.phase_history.len() - MAX_PHASE_HISTORY;
+            self.phase_history.drain(..excess);
+        }
+    }
+
+    /// Add a patch run to the history
+    pub fn record_patch(&mut self, record: PatchRecord) {
+        self.patch_history.push(record);
+
+        if self.patch_history.len() > MAX_PATCH_HISTORY {
+            let excess = self.patch_history.len() - MAX_PATCH_HISTORY;
+            self.patch_history.drain(..excess);
+        }
+    }
+
+    /// Set the outcome of the last patch run
+    pub fn finish_patch(&mut self, outcome: PatchOutcome) {
+        if let Some(record) = self.patch_history.last_mut() {
+            record.finished = Some(Utc::now());
+
+            if outcome == PatchOutcome::Applied {
+                if let Some(version) = record.version {
+                    self.patch_version = Some(version);
+                }
+            }
+
+            record.outcome = outcome;
         }
     }
 
+    /// Returns whether a patch with the hash was applied before
+    pub fn patch_hash_applied(&self, sha256: &str) -> bool {
+        self.patch_history
+            .iter()
+            .any(|record| record.sha256 == sha256 && record.outcome == PatchOutcome::Applied)
+    }
+
+    /// Add a self-update to the history
+    pub fn record_self_update(&mut self, record: SelfUpdateRecord) {
+        self.self_update_history.push(record);
+
+        if self.self_update_history.len() > MAX_SELF_UPDATE_HISTORY {
+            let excess = self.self_update_history.len() - MAX_SELF_UPDATE_HISTORY;
+            self.self_update_history.drain(..excess);
+        }
+    }
+
+    /// Returns the self-update waiting for the payload to be ready
+    pub fn pending_self_update(&mut self) -> Option<&mut SelfUpdateRecord> {
+        self.self_update_history
+            .last_mut()
+            .filter(|record| record.outcome == SelfUpdateOutcome::Pending)
+    }
+
     pub fn load(path: &Path) -> anyhow::Result<Self> {
         let buf = std::fs::read_to_string(path)?;
-        let doc = serde_json::from_str::<State>(&buf)?;
+        let mut doc = serde_json::from_str::<State>(&buf)?;
+
+        if doc.uuid.is_nil() {
+            doc.uuid = Uuid::new_v4();
+        }
 
         Ok(doc)
     }
@@ -36,8 +140,45 @@ impl State {
         }
 
         let buf = serde_json::to_string_pretty(self)?;
-        std::fs::write(path, buf.as_bytes())?;
+
+        // Replace the file in one step so a power loss can't leave it truncated
+        let temp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(buf.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_generates_missing_uuid() {
+        let path = std::env::temp_dir().join(format!(
+            "warrior4-appliance-test-{}-state-uuid.json",
+            std::process::id()
+        ));
+
+        std::fs::write(&path, r#"{"boot_count": 3}"#).unwrap();
+        let state = State::load(&path).unwrap();
+        assert!(!state.uuid.is_nil());
+        assert_eq!(state.boot_count, 3);
+
+        std::fs::write(&path, r#"{"uuid": "00000000-0000-0000-0000-000000000000"}"#).unwrap();
+        assert!(!State::load(&path).unwrap().uuid.is_nil());
+
+        let saved = State::new();
+        saved.save(&path).unwrap();
+        assert_eq!(State::load(&path).unwrap().uuid, saved.uuid);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
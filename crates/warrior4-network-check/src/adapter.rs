@@ -1,37 +1,112 @@
 use std::{
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, SocketAddr},
     ops::Deref,
-    sync::RwLock,
+    sync::{Arc, Mutex, mpsc},
+    time::{Duration, Instant},
 };
 
-use dnsclient::sync::DNSClient;
-use ureq::unversioned::resolver::{ResolvedSocketAddrs, Resolver};
+use ureq::{
+    config::IpFamily,
+    unversioned::resolver::{ResolvedSocketAddrs, Resolver},
+};
 use url::Url;
 
+use crate::dns::{self, DnsAnswer};
+
+/// Capacity of `ResolvedSocketAddrs`
+const MAX_ADDRESSES: usize = 16;
+
 /// Adapter for dnsclient to ureq.
+///
+/// Every upstream server is queried at the same time and the first server
+/// that answers (with addresses or that the name does not exist) is used.
 #[derive(Debug)]
 pub struct DnsClientAdapter {
-    client: RwLock<DNSClient>,
+    servers: Vec<SocketAddr>,
+    /// Maximum time to wait for each server
+    server_timeout: Duration,
+    upstream: Arc<Mutex<Option<SocketAddr>>>,
 }
 
 impl DnsClientAdapter {
-    pub fn new(client: DNSClient) -> Self {
+    pub fn new(servers: Vec<SocketAddr>, server_timeout: Duration) -> Self {
         Self {
-            client: RwLock::new(client),
+            servers,
+            server_timeout,
+            upstream: Arc::new(Mutex::new(None)),
         }
     }
+
+    /// Returns a handle to the server that answered the latest query.
+    pub fn upstream(&self) -> Arc<Mutex<Option<SocketAddr>>> {
+        self.upstream.clone()
+    }
+
+    /// Query the servers in parallel and returns the server that answered
+    /// first and its addresses, which are empty if the name does not exist.
+    pub fn lookup(
+        &self,
+        host: &str,
+        timeout: Duration,
+    ) -> std::io::Result<(SocketAddr, Vec<IpAddr>)> {
+        *self.upstream.lock().unwrap() = None;
+
+        if self.servers.is_empty() {
+            return Err(std::io::Error::other("no DNS servers"));
+        }
+
+        let timeout = timeout.min(self.server_timeout);
+        let (sender, receiver) = mpsc::channel();
+
+        // The threads of slower servers finish on their own after the timeout
+        for server in &self.servers {
+            let server = *server;
+            let host = host.to_string();
+            let sender = sender.clone();
+
+            std::thread::spawn(move || {
+                let _ = sender.send((server, dns::resolve(server, &host, timeout)));
+            });
+        }
+        drop(sender);
+
+        let deadline = Instant::now() + timeout;
+        let mut last_error = None;
+
+        while let Ok((server, answer)) =
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            match answer {
+                DnsAnswer::Addresses { addresses } => {
+                    *self.upstream.lock().unwrap() = Some(server);
+                    return Ok((server, addresses));
+                }
+                DnsAnswer::NxDomain => {
+                    *self.upstream.lock().unwrap() = Some(server);
+                    return Ok((server, Vec::new()));
+                }
+                DnsAnswer::NoResponse => {}
+                DnsAnswer::Error { message } => last_error = Some(format!("{server}: {message}")),
+            }
+        }
+
+        Err(match last_error {
+            Some(message) => std::io::Error::other(message),
+            None => std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "No response received from any servers",
+            ),
+        })
+    }
 }
 
 impl Resolver for DnsClientAdapter {
     fn resolve(
         &self,
         uri: &ureq::http::Uri,
-        _config: &ureq::config::Config,
+        config: &ureq::config::Config,
         timeout: ureq::unversioned::transport::NextTimeout,
     ) -> Result<ResolvedSocketAddrs, ureq::Error> {
-        let mut client = self.client.write().unwrap();
-        client.set_timeout(timeout.after.deref().to_owned());
-
         let url =
             Url::parse(&uri.to_string()).map_err(|error| ureq::Error::BadUri(error.to_string()))?;
 
@@ -42,21 +117,264 @@ impl Resolver for DnsClientAdapter {
             .port_or_known_default()
             .ok_or_else(|| ureq::Error::BadUri("port".to_string()))?;
 
-        let addresses = client.query_addrs(host)?;
+        let (_server, addresses) = self.lookup(host, *timeout.after.deref())?;
+
+        let socket_addresses = socket_addresses(self.empty(), &addresses, port, config.ip_family());
 
-        if addresses.is_empty() {
+        if socket_addresses.is_empty() {
             return Err(ureq::Error::HostNotFound);
         }
 
-        let mut socket_addresses =
-            ResolvedSocketAddrs::from_fn(|_i| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+        Ok(socket_addresses)
+    }
+}
 
-        for address in addresses.iter().take(16) {
-            socket_addresses.push(SocketAddr::new(*address, port));
+/// Fill the array with the addresses of the wanted family
+///
+/// The array holds at most 16 addresses, so the families are interleaved to
+/// keep addresses of both when there are more.
+fn socket_addresses(
+    mut socket_addresses: ResolvedSocketAddrs,
+    addresses: &[IpAddr],
+    port: u16,
+    ip_family: IpFamily,
+) -> ResolvedSocketAddrs {
+    let mut ipv4 = addresses.iter().filter(|address| address.is_ipv4());
+    let mut ipv6 = addresses.iter().filter(|address| address.is_ipv6());
+    let interleaved = std::iter::from_fn(|| match (ipv4.next(), ipv6.next()) {
+        (None, None) => None,
+        (a, b) => Some(a.into_iter().chain(b)),
+    })
+    .flatten()
+    .map(|address| SocketAddr::new(*address, port));
+
+    for address in ip_family.keep_wanted(interleaved).take(MAX_ADDRESSES) {
+        socket_addresses.push(address);
+    }
+
+    socket_addresses
+}
+
+/// Resolver that remembers the addresses returned by another resolver.
+#[derive(Debug)]
+pub struct RecordingResolver<R> {
+    inner: R,
+    addresses: Arc<Mutex<Vec<IpAddr>>>,
+}
+
+impl<R> RecordingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            addresses: Arc::new(Mutex::new(Vec::new())),
         }
+    }
+
+    /// Returns a handle to the addresses of the latest resolution.
+    pub fn addresses(&self) -> Arc<Mutex<Vec<IpAddr>>> {
+        self.addresses.clone()
+    }
+}
 
-        assert!(!socket_addresses.is_empty());
+impl<R: Resolver> Resolver for RecordingResolver<R> {
+    fn resolve(
+        &self,
+        uri: &ureq::http::Uri,
+        config: &ureq::config::Config,
+        timeout: ureq::unversioned::transport::NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let result = self.inner.resolve(uri, config, timeout);
 
-        Ok(socket_addresses)
+        if let Ok(socket_addresses) = &result {
+            let mut addresses = self.addresses.lock().unwrap();
+            addresses.clear();
+            addresses.extend(socket_addresses.iter().map(|address| address.ip()));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, UdpSocket};
+
+    use super::*;
+
+    enum FakeAnswer {
+        Addresses(Vec<IpAddr>),
+        NxDomain,
+        /// Never respond
+        Silent,
+    }
+
+    /// Start a DNS server on localhost that gives the same answer to every query
+    fn spawn_fake_server(answer: FakeAnswer) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+
+            loop {
+                let Ok((length, peer)) = socket.recv_from(&mut buf) else {
+                    return;
+                };
+
+                if let Some(response) = fake_response(&buf[..length], &answer) {
+                    let _ = socket.send_to(&response, peer);
+                }
+            }
+        });
+
+        address
+    }
+
+    fn fake_response(query: &[u8], answer: &FakeAnswer) -> Option<Vec<u8>> {
+        // Question name labels, then the type and class
+        let mut end = 12;
+        while query[end] != 0 {
+            end += query[end] as usize + 1;
+        }
+        let question = &query[12..end + 5];
+        let query_type = u16::from_be_bytes([query[end + 1], query[end + 2]]);
+
+        let records = match answer {
+            FakeAnswer::Addresses(addresses) => addresses
+                .iter()
+                .filter_map(|address| match (address, query_type) {
+                    (IpAddr::V4(address), 1) => Some((1u16, address.octets().to_vec())),
+                    (IpAddr::V6(address), 28) => Some((28u16, address.octets().to_vec())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            FakeAnswer::NxDomain => Vec::new(),
+            FakeAnswer::Silent => return None,
+        };
+        let flags: u16 = match answer {
+            FakeAnswer::NxDomain => 0x8183,
+            _ => 0x8180,
+        };
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&query[0..2]);
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        response.extend_from_slice(question);
+
+        for (record_type, data) in records {
+            response.extend_from_slice(&[0xc0, 0x0c]);
+            response.extend_from_slice(&record_type.to_be_bytes());
+            response.extend_from_slice(&1u16.to_be_bytes());
+            response.extend_from_slice(&60u32.to_be_bytes());
+            response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            response.extend_from_slice(&data);
+        }
+
+        Some(response)
+    }
+
+    #[test]
+    fn lookup_returns_exact_addresses() {
+        let addresses = vec![
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+        ];
+        let server = spawn_fake_server(FakeAnswer::Addresses(addresses.clone()));
+        let adapter = DnsClientAdapter::new(vec![server], Duration::from_secs(2));
+
+        let (upstream, resolved) = adapter
+            .lookup("example.test", Duration::from_secs(2))
+            .unwrap();
+
+        assert_eq!(upstream, server);
+        assert_eq!(resolved, addresses);
+        assert_eq!(*adapter.upstream().lock().unwrap(), Some(server));
+    }
+
+    #[test]
+    fn lookup_nxdomain_is_empty() {
+        let server = spawn_fake_server(FakeAnswer::NxDomain);
+        let adapter = DnsClientAdapter::new(vec![server], Duration::from_secs(2));
+
+        let (upstream, resolved) = adapter
+            .lookup("nonexistent.test", Duration::from_secs(2))
+            .unwrap();
+
+        assert_eq!(upstream, server);
+        assert!(resolved.is_empty());
+    }
+
+    #[test]
+    fn lookup_does_not_wait_for_silent_server() {
+        let silent = spawn_fake_server(FakeAnswer::Silent);
+        let answering = spawn_fake_server(FakeAnswer::Addresses(vec![IpAddr::V4(Ipv4Addr::new(
+            192, 0, 2, 1,
+        ))]));
+        let adapter = DnsClientAdapter::new(vec![silent, answering], Duration::from_secs(5));
+
+        let start = Instant::now();
+        let (upstream, resolved) = adapter
+            .lookup("example.test", Duration::from_secs(5))
+            .unwrap();
+
+        assert_eq!(upstream, answering);
+        assert_eq!(resolved, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn lookup_times_out_per_server() {
+        let servers = vec![
+            spawn_fake_server(FakeAnswer::Silent),
+            spawn_fake_server(FakeAnswer::Silent),
+        ];
+        let adapter = DnsClientAdapter::new(servers, Duration::from_millis(300));
+
+        let start = Instant::now();
+        let error = adapter
+            .lookup("example.test", Duration::from_secs(30))
+            .unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(*adapter.upstream().lock().unwrap(), None);
+    }
+
+    #[test]
+    fn socket_addresses_has_no_placeholders() {
+        let addresses = [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))];
+        let adapter = DnsClientAdapter::new(Vec::new(), Duration::from_secs(1));
+
+        let result = socket_addresses(adapter.empty(), &addresses, 80, IpFamily::Any);
+
+        assert_eq!(
+            result.iter().copied().collect::<Vec<_>>(),
+            vec![SocketAddr::new(addresses[0], 80)]
+        );
+    }
+
+    #[test]
+    fn socket_addresses_keeps_both_families_when_truncated() {
+        let mut addresses = (1..=20)
+            .map(|i| IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)))
+            .collect::<Vec<_>>();
+        addresses.push(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        let adapter = DnsClientAdapter::new(Vec::new(), Duration::from_secs(1));
+
+        let result = socket_addresses(adapter.empty(), &addresses, 80, IpFamily::Any);
+
+        assert_eq!(result.len(), MAX_ADDRESSES);
+        assert!(result.iter().any(|address| address.is_ipv6()));
+
+        let result = socket_addresses(adapter.empty(), &addresses, 80, IpFamily::Ipv6Only);
+
+        assert_eq!(
+            result.iter().copied().collect::<Vec<_>>(),
+            vec![SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 80)]
+        );
     }
 }
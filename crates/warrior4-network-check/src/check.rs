@@ -1,17 +1,42 @@
-use std::{fmt::Display, net::SocketAddr, time::Duration};
+use std::{
+    fmt::Display,
+    io::Read,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use dnsclient::{UpstreamServer, sync::DNSClient};
 use rand::distr::{Alphanumeric, SampleString};
-use ureq::{Agent, unversioned::transport::DefaultConnector};
+use serde::{Serialize, ser::SerializeMap};
+use sha2::{Digest, Sha256};
+use ureq::{
+    Agent,
+    config::IpFamily,
+    unversioned::{resolver::DefaultResolver, transport::DefaultConnector},
+};
 
-use crate::{adapter::DnsClientAdapter, config::TargetConfig};
+use crate::{
+    adapter::{DnsClientAdapter, RecordingResolver},
+    config::{CheckConfig, CheckKind, ResolverChoice, TargetConfig, url_host},
+    dns::{self, DnsFinding, DnsResult, Resolver},
+    interference::{self, Interference},
+    measure::Measurement,
+    tls::{self, CertificateChain},
+};
+
+/// Maximum length of the content snippet in the report
+const SNIPPET_LENGTH: usize = 256;
+const TLS_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub enum TestResult {
     Incomplete,
     Pass,
     Fail(String),
-    Error(Box<dyn std::error::Error>),
+    /// The network modified the traffic in a recognized way
+    Intercepted(Interference),
+    Error(String),
 }
 
 impl TestResult {
@@ -20,81 +45,369 @@ impl TestResult {
     }
 }
 
-impl Default for TestResult {
-    fn default() -> Self {
-        Self::Incomplete
-    }
-}
-
 impl Display for TestResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TestResult::Incomplete => write!(f, "incomplete"),
             TestResult::Pass => write!(f, "pass"),
             TestResult::Fail(message) => write!(f, "fail: {message}"),
+            TestResult::Intercepted(interference) => write!(f, "intercepted: {interference}"),
             TestResult::Error(error) => write!(f, "error: {error}"),
         }
     }
 }
 
-#[derive(Debug, Default)]
+impl Serialize for TestResult {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+
+        match self {
+            TestResult::Incomplete => map.serialize_entry("outcome", "incomplete")?,
+            TestResult::Pass => map.serialize_entry("outcome", "pass")?,
+            TestResult::Fail(message) => {
+                map.serialize_entry("outcome", "fail")?;
+                map.serialize_entry("message", message)?;
+            }
+            TestResult::Intercepted(interference) => {
+                map.serialize_entry("outcome", "intercepted")?;
+                map.serialize_entry("message", &interference.to_string())?;
+                map.serialize_entry("interference", interference)?;
+            }
+            TestResult::Error(message) => {
+                map.serialize_entry("outcome", "error")?;
+                map.serialize_entry("message", message)?;
+            }
+        }
+
+        map.end()
+    }
+}
+
+/// Category of an error so the cause can be explained without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    HostNotFound,
+    Timeout,
+    ConnectionRefused,
+    ConnectionFailed,
+    Tls,
+    Protocol,
+    Io,
+    Other,
+}
+
+impl ErrorKind {
+    fn from_ureq(error: &ureq::Error) -> Self {
+        match error {
+            ureq::Error::HostNotFound => Self::HostNotFound,
+            ureq::Error::Timeout(_) => Self::Timeout,
+            ureq::Error::ConnectionFailed => Self::ConnectionFailed,
+            ureq::Error::Tls(_) | ureq::Error::Rustls(_) | ureq::Error::Pem(_) => Self::Tls,
+            ureq::Error::Protocol(_)
+            | ureq::Error::Http(_)
+            | ureq::Error::LargeResponseHeader(_, _)
+            | ureq::Error::TooManyRedirects
+            | ureq::Error::RedirectFailed => Self::Protocol,
+            ureq::Error::Io(error) => Self::from_io(error),
+            _ => Self::Other,
+        }
+    }
+
+    fn from_io(error: &std::io::Error) -> Self {
+        match error.kind() {
+            // The system resolver's lookup errors don't have an error kind
+            _ if error.to_string().contains("failed to lookup address") => Self::HostNotFound,
+            std::io::ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Self::Timeout,
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::HostUnreachable
+            | std::io::ErrorKind::NetworkUnreachable => Self::ConnectionFailed,
+            // Errors from rustls are wrapped as InvalidData
+            std::io::ErrorKind::InvalidData => Self::Tls,
+            _ => Self::Io,
+        }
+    }
+}
+
+/// Result and details of a single test
+#[derive(Debug, Serialize)]
+pub struct TestReport {
+    pub name: String,
+    /// Whether a failure fails the whole check
+    pub required: bool,
+    pub url: String,
+    #[serde(flatten)]
+    pub result: TestResult,
+    pub duration_ms: u64,
+    /// Addresses the host name resolved to
+    pub addresses: Vec<IpAddr>,
+    pub status_code: Option<u16>,
+    pub error_kind: Option<ErrorKind>,
+    /// Bootstrap DNS server that answered the lookup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns_upstream: Option<SocketAddr>,
+    /// Start of the response body if it was not the expected content
+    pub snippet: Option<String>,
+    /// Response headers that proxies add
+    pub proxy_headers: Vec<String>,
+    /// Answers of each resolver for the DNS test
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_results: Vec<DnsResult>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dns_findings: Vec<DnsFinding>,
+    /// Certificates presented by the server for the TLS test
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub certificate_chain: Option<CertificateChain>,
+}
+
+impl TestReport {
+    fn new(name: &str, url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            required: true,
+            url: url.to_string(),
+            result: TestResult::Incomplete,
+            duration_ms: 0,
+            addresses: Vec::new(),
+            status_code: None,
+            error_kind: None,
+            dns_upstream: None,
+            snippet: None,
+            proxy_headers: Vec::new(),
+            dns_results: Vec::new(),
+            dns_findings: Vec::new(),
+            certificate_chain: None,
+        }
+    }
+
+    fn set_error(&mut self, error: ureq::Error) {
+        self.error_kind = Some(ErrorKind::from_ureq(&error));
+        self.result = TestResult::Error(error.to_string());
+    }
+
+    fn set_io_error(&mut self, error: std::io::Error) {
+        self.error_kind = Some(ErrorKind::from_io(&error));
+        self.result = TestResult::Error(error.to_string());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn ip_family(self) -> IpFamily {
+        match self {
+            AddressFamily::Ipv4 => IpFamily::Ipv4Only,
+            AddressFamily::Ipv6 => IpFamily::Ipv6Only,
+        }
+    }
+
+    fn contains(self, address: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4 => address.is_ipv4(),
+            AddressFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+impl Display for AddressFamily {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressFamily::Ipv4 => write!(f, "IPv4"),
+            AddressFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Results of the HTTP tests limited to a single address family
+#[derive(Debug, Serialize)]
+pub struct FamilyReport {
+    pub family: AddressFamily,
+    /// Whether the hosts have addresses of this family
+    pub available: bool,
+    pub passed: bool,
+    pub tests: Vec<TestReport>,
+}
+
+#[derive(Debug, Default, Serialize)]
 pub struct Report {
-    pub nonexistent: TestResult,
-    pub cleartext: TestResult,
-    pub target: TestResult,
+    pub passed: bool,
+    pub tests: Vec<TestReport>,
+    /// The family results don't affect `passed` because connections fall
+    /// back to the other family
+    pub families: Vec<FamilyReport>,
+    /// Result of the `--measure` mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<Box<Measurement>>,
 }
 
 impl Report {
+    /// Returns the first test with the name
+    pub fn test(&self, name: &str) -> Option<&TestReport> {
+        self.tests.iter().find(|test| test.name == name)
+    }
+
     pub fn is_pass(&self) -> bool {
-        self.nonexistent.is_pass() && self.cleartext.is_pass() && self.target.is_pass()
+        self.tests
+            .iter()
+            .filter(|test| test.required)
+            .all(|test| test.result.is_pass())
+    }
+}
+
+/// An HTTP client with the addresses its resolver returned
+struct Client {
+    agent: Agent,
+    addresses: Arc<Mutex<Vec<IpAddr>>>,
+    /// DNS server that answered, if the resolver queries the bootstrap servers
+    upstream: Option<Arc<Mutex<Option<SocketAddr>>>>,
+}
+
+impl Client {
+    fn new<R: ureq::unversioned::resolver::Resolver>(
+        resolver: R,
+        ip_family: IpFamily,
+        timeout: Duration,
+    ) -> Self {
+        let resolver = RecordingResolver::new(resolver);
+        let addresses = resolver.addresses();
+        let agent = Agent::with_parts(
+            Agent::config_builder()
+                .timeout_global(Some(timeout))
+                .max_redirects(0)
+                .http_status_as_error(false)
+                .ip_family(ip_family)
+                .build(),
+            DefaultConnector::new(),
+            resolver,
+        );
+
+        Self {
+            agent,
+            addresses,
+            upstream: None,
+        }
+    }
+
+    /// Client that resolves with the bootstrap DNS servers
+    fn bootstrap(config: &TargetConfig, ip_family: IpFamily) -> Self {
+        let resolver = bootstrap_resolver(config);
+        let upstream = resolver.upstream();
+
+        Self {
+            upstream: Some(upstream),
+            ..Self::new(resolver, ip_family, config.http_timeout())
+        }
+    }
+
+    /// Run the test and fill in the timing and addresses
+    fn run<F>(&self, report: &mut TestReport, test: F)
+    where
+        F: FnOnce(&Agent, &mut TestReport),
+    {
+        self.addresses.lock().unwrap().clear();
+
+        let start = Instant::now();
+        test(&self.agent, report);
+
+        report.duration_ms = start.elapsed().as_millis() as u64;
+        report.addresses = self.addresses.lock().unwrap().clone();
+        report.dns_upstream = self
+            .upstream
+            .as_ref()
+            .and_then(|upstream| *upstream.lock().unwrap());
     }
 }
 
 pub fn check_network(config: &TargetConfig) -> Result<Report, (Report, std::io::Error)> {
     let mut report = Report::default();
-    let custom_client = Agent::with_parts(
-        Agent::config_builder()
-            .timeout_global(Some(Duration::from_secs(30)))
-            .max_redirects(0)
-            .build(),
-        DefaultConnector::new(),
-        DnsClientAdapter::new(custom_dns_client(config)),
-    );
-    let system_client = Agent::config_builder()
-        .timeout_global(Some(Duration::from_secs(30)))
-        .max_redirects(0)
-        .build()
-        .new_agent();
-
-    let url = format_random_domain(&config.nonexistent_url);
-    eprint!("Check nonexistent resource ({url}) ... ");
-    let result = check_nonexistent(&custom_client, &url);
-    eprintln!("{result}");
-    report.nonexistent = result;
-
-    let url = &config.cleartext_url;
-    eprint!("Check cleartext resource ({url}) ... ");
-    let result = check_content(&custom_client, url, &config.content);
-    eprintln!("{result}");
-    report.cleartext = result;
-
-    let url = &config.target_url;
-    eprint!("Check target resource ({url}) ... ");
-    let result = check_content(&system_client, url, &config.content);
-    eprintln!("{result}");
-    report.target = result;
+    let checks = config.all_checks();
 
-    Ok(report)
-}
+    for check in &checks {
+        let target = match check.target() {
+            Ok(target) if check.kind == CheckKind::NonexistentDomain => {
+                format_random_domain(target)
+            }
+            Ok(target) => target.to_string(),
+            Err(_) => String::new(),
+        };
 
-fn custom_dns_client(config: &TargetConfig) -> DNSClient {
-    let servers = config
-        .bootstrap_dns
+        if target.is_empty() {
+            eprint!("Check {} ... ", check.name);
+        } else {
+            eprint!("Check {} ({target}) ... ", check.name);
+        }
+
+        let mut test = TestReport::new(&check.name, &target);
+        test.required = check.required;
+        if let Err(error) = run_check(config, check, IpFamily::Any, &mut test) {
+            test.result = TestResult::Error(format!("{error:#}"));
+        }
+        eprintln!(
+            "{}{}",
+            test.result,
+            if check.required { "" } else { " (advisory)" }
+        );
+        for finding in &test.dns_findings {
+            eprintln!("    {finding}");
+        }
+        report.tests.push(test);
+    }
+
+    for (index, check) in checks.iter().enumerate() {
+        let Some(baseline) = check
+            .proxy_header_baseline
+            .as_deref()
+            .and_then(|name| report.test(name))
+            .map(|test| test.proxy_headers.clone())
+        else {
+            continue;
+        };
+
+        check_added_proxy_headers(&mut report.tests[index], &baseline);
+    }
+
+    let family_checks = checks
         .iter()
-        .map(|i| UpstreamServer::new(SocketAddr::new(*i, 53)))
-        .collect();
+        .zip(&report.tests)
+        .filter(|(check, _test)| check.families)
+        .collect::<Vec<_>>();
+
+    report.families = std::thread::scope(|scope| {
+        let family_checks = &family_checks;
+        let handles = [AddressFamily::Ipv4, AddressFamily::Ipv6]
+            .map(|family| scope.spawn(move || check_family(config, family, family_checks)));
+
+        handles.map(|handle| handle.join().unwrap())
+    })
+    .into();
+
+    for family in &report.families {
+        for test in &family.tests {
+            eprintln!(
+                "Check {} resource over {} ... {}",
+                test.name, family.family, test.result
+            );
+        }
+
+        if !family.available {
+            eprintln!("No {} addresses", family.family);
+        }
+    }
+
+    report.passed = report.is_pass();
+
+    Ok(report)
+}
 
-    DNSClient::new(servers)
+fn bootstrap_resolver(config: &TargetConfig) -> DnsClientAdapter {
+    DnsClientAdapter::new(config.bootstrap_servers(), config.dns_timeout())
 }
 
 fn format_random_domain(template: &str) -> String {
@@ -102,37 +415,460 @@ fn format_random_domain(template: &str) -> String {
     template.replace("{random}", &chars)
 }
 
-fn check_nonexistent(client: &Agent, url: &str) -> TestResult {
-    let url = format_random_domain(url);
+fn check_nonexistent(client: &Agent, test: &mut TestReport) {
+    match client.get(&test.url).call() {
+        Ok(response) => {
+            test.status_code = Some(response.status().as_u16());
+            test.result = TestResult::Fail("unexpected response".to_string());
+        }
+        Err(error) if ErrorKind::from_ureq(&error) == ErrorKind::HostNotFound => {
+            test.result = TestResult::Pass
+        }
+        Err(error) => test.set_error(error),
+    }
+}
 
-    match client.get(&url).call() {
-        Ok(_response) => TestResult::Fail("unexpected response".to_string()),
-        Err(error) => match error {
-            ureq::Error::HostNotFound => TestResult::Pass,
+/// Expected body of a content test
+#[derive(Debug, Clone, Copy)]
+enum Expected<'a> {
+    Content(&'a str),
+    /// Hex encoded SHA-256 hash
+    Sha256(&'a str),
+}
 
-            _ => TestResult::Error(Box::new(error)),
-        },
+impl Expected<'_> {
+    fn matches(&self, content: &[u8]) -> bool {
+        match self {
+            Expected::Content(expected) => content == expected.as_bytes(),
+            Expected::Sha256(expected) => {
+                format!("{:x}", Sha256::digest(content)).eq_ignore_ascii_case(expected)
+            }
+        }
     }
 }
 
-fn check_content(client: &Agent, url: &str, expected_content: &str) -> TestResult {
-    match client.get(url).call() {
+fn check_content(client: &Agent, test: &mut TestReport, expected: Expected) {
+    match client.get(&test.url).call() {
         Ok(mut response) => {
+            test.status_code = Some(response.status().as_u16());
+            test.proxy_headers = interference::find_proxy_headers(response.headers());
+
+            if let Some(redirect) =
+                interference::check_redirect(response.status(), response.headers())
+            {
+                test.result = TestResult::Intercepted(redirect);
+                return;
+            }
+
             if response.status() != 200 {
-                return TestResult::Fail(format!("unexpected status code {}", response.status()));
+                test.result =
+                    TestResult::Fail(format!("unexpected status code {}", response.status()));
+                return;
             }
 
+            let declared_length = interference::content_length(response.headers());
             let content = response.body_mut().read_to_vec().unwrap_or_default();
 
-            if content != expected_content.as_bytes() {
+            if !expected.matches(&content) {
                 let mut snippet = content.escape_ascii().to_string();
-                snippet.truncate(64);
+                snippet.truncate(SNIPPET_LENGTH);
+
+                let mut message_snippet = snippet.clone();
+                message_snippet.truncate(64);
+
+                // Only the injected HTML can be recognized without the expected content
+                let (expected_content, declared_length) = match expected {
+                    Expected::Content(expected) => (expected.as_bytes(), declared_length),
+                    Expected::Sha256(_) => (&b""[..], None),
+                };
 
-                TestResult::Fail(format!("unexpected content '{snippet}'",))
+                test.result = match interference::classify_content(
+                    &content,
+                    expected_content,
+                    declared_length,
+                ) {
+                    Some(interference) => TestResult::Intercepted(interference),
+                    None => TestResult::Fail(format!("unexpected content '{message_snippet}'",)),
+                };
+                test.snippet = Some(snippet);
             } else {
+                test.result = TestResult::Pass;
+            }
+        }
+        Err(error) => test.set_error(error),
+    }
+}
+
+/// Run the checks again using only addresses of the family
+///
+/// A check is only run if its host had addresses of the family in the
+/// test of the unrestricted run.
+fn check_family(
+    config: &TargetConfig,
+    family: AddressFamily,
+    checks: &[(&CheckConfig, &TestReport)],
+) -> FamilyReport {
+    let has_family =
+        |addresses: &[IpAddr]| addresses.iter().any(|address| family.contains(address));
+
+    let mut report = FamilyReport {
+        family,
+        available: checks
+            .iter()
+            .any(|(_check, test)| has_family(&test.addresses)),
+        passed: false,
+        tests: Vec::new(),
+    };
+
+    for (check, unrestricted_test) in checks {
+        if !has_family(&unrestricted_test.addresses) {
+            continue;
+        }
+
+        let mut test = TestReport::new(&check.name, &unrestricted_test.url);
+        test.required = check.required;
+        if let Err(error) = run_check(config, check, family.ip_family(), &mut test) {
+            test.result = TestResult::Error(format!("{error:#}"));
+        }
+        report.tests.push(test);
+    }
+
+    report.passed = report.available && report.tests.iter().all(|test| test.result.is_pass());
+
+    report
+}
+
+/// Mark the test as intercepted if its response has proxy headers that the
+/// baseline response does not have
+fn check_added_proxy_headers(test: &mut TestReport, baseline: &[String]) {
+    if !test.result.is_pass() {
+        return;
+    }
+
+    let headers = test
+        .proxy_headers
+        .iter()
+        .filter(|header| !baseline.contains(header))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if !headers.is_empty() {
+        test.result = TestResult::Intercepted(Interference::ProxyHeaders { headers });
+    }
+}
+
+/// Compare the answers of the bootstrap resolvers and the system resolvers
+fn check_dns(config: &TargetConfig, check: &CheckConfig, test: &mut TestReport) {
+    let mut resolvers = config
+        .bootstrap_servers()
+        .into_iter()
+        .map(|address| Resolver {
+            address,
+            system: false,
+        })
+        .collect::<Vec<_>>();
+
+    let system_servers = match &config.system_dns {
+        Some(servers) => servers.clone(),
+        None => match dnsclient::system::default_resolvers() {
+            Ok(servers) => servers.into_iter().map(|server| server.addr).collect(),
+            Err(error) => {
+                test.result = TestResult::Error(format!("system resolvers: {error}"));
+                return;
+            }
+        },
+    };
+
+    for address in system_servers {
+        match resolvers
+            .iter_mut()
+            .find(|resolver| resolver.address == address)
+        {
+            Some(resolver) => resolver.system = true,
+            None => resolvers.push(Resolver {
+                address,
+                system: true,
+            }),
+        }
+    }
+
+    let nonexistent_name = check
+        .url
+        .as_deref()
+        .map(|url| url_host(&format_random_domain(url)))
+        .unwrap_or_default();
+    let mut names = Vec::new();
+
+    for name in std::iter::once(&nonexistent_name).chain(&check.names) {
+        if !name.is_empty() && !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+
+    test.dns_results = dns::resolve_all(&resolvers, &names, config.dns_timeout());
+    test.dns_findings = dns::compare(&test.dns_results, &nonexistent_name);
+
+    let interference = test
+        .dns_findings
+        .iter()
+        .filter(|finding| finding.is_interference())
+        .map(|finding| finding.to_string())
+        .collect::<Vec<_>>();
+    let all_no_response = test
+        .dns_findings
+        .iter()
+        .filter(|finding| matches!(finding, DnsFinding::NoResponse { .. }))
+        .count()
+        == resolvers.len();
+
+    test.result = if !interference.is_empty() {
+        TestResult::Fail(interference.join("; "))
+    } else if all_no_response {
+        test.error_kind = Some(ErrorKind::Timeout);
+        TestResult::Error("no DNS resolver responded".to_string())
+    } else {
+        TestResult::Pass
+    };
+}
+
+/// Compare the target's certificate chain with the expected pins and issuers
+/// to detect proxies that decrypt traffic with their own root certificate
+fn check_tls(check: &CheckConfig, test: &mut TestReport) {
+    let url = match url::Url::parse(&test.url) {
+        Ok(url) => url,
+        Err(error) => {
+            test.result = TestResult::Error(error.to_string());
+            return;
+        }
+    };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        test.result = TestResult::Error("URL has no host".to_string());
+        return;
+    };
+
+    let chain = match tls::fetch_chain(host, port, TLS_TIMEOUT) {
+        Ok(chain) => chain,
+        Err(error) => {
+            test.set_io_error(error);
+            return;
+        }
+    };
+
+    let has_expectations = !check.spki_pins.is_empty() || !check.issuers.is_empty();
+
+    test.result = if !has_expectations || chain.matches(&check.spki_pins, &check.issuers) {
+        TestResult::Pass
+    } else {
+        TestResult::Intercepted(Interference::TlsInterception {
+            issuer: chain.issuer().to_string(),
+            trusted: chain.trusted,
+        })
+    };
+    test.certificate_chain = Some(chain);
+}
+
+/// Run a check from the config's list of checks
+///
+/// HTTP checks only connect to addresses of the IP family.
+fn run_check(
+    config: &TargetConfig,
+    check: &CheckConfig,
+    ip_family: IpFamily,
+    test: &mut TestReport,
+) -> anyhow::Result<()> {
+    // Configs that were not read by load_config have not been validated
+    check.validate()?;
+
+    let client = || match check.resolver {
+        ResolverChoice::System => {
+            Client::new(DefaultResolver::default(), ip_family, config.http_timeout())
+        }
+        ResolverChoice::Bootstrap => Client::bootstrap(config, ip_family),
+    };
+    let start = Instant::now();
+
+    match check.kind {
+        CheckKind::NonexistentDomain => client().run(test, check_nonexistent),
+        CheckKind::ContentMatch => {
+            let expected = match (&check.content, &check.sha256) {
+                (Some(content), _) => Expected::Content(content),
+                (None, Some(hash)) => Expected::Sha256(hash),
+                (None, None) => anyhow::bail!("check {} requires content or sha256", check.name),
+            };
+            client().run(test, |agent, test| check_content(agent, test, expected));
+        }
+        CheckKind::StatusOnly => {
+            client().run(test, |agent, test| check_status(agent, test, check.status))
+        }
+        CheckKind::TcpConnect => {
+            check_tcp_connect(config, check.resolver, check.banner.as_deref(), test);
+            test.duration_ms = start.elapsed().as_millis() as u64;
+        }
+        CheckKind::DnsResolve => {
+            match resolve_host(config, check.resolver, &test.url) {
+                Ok(addresses) if addresses.is_empty() => {
+                    test.error_kind = Some(ErrorKind::HostNotFound);
+                    test.result = TestResult::Fail("no addresses".to_string());
+                }
+                Ok(addresses) => {
+                    test.addresses = addresses;
+                    test.result = TestResult::Pass;
+                }
+                Err(error) => test.set_io_error(error),
+            }
+            test.duration_ms = start.elapsed().as_millis() as u64;
+        }
+        CheckKind::TlsCertificate => {
+            check_tls(check, test);
+            test.duration_ms = start.elapsed().as_millis() as u64;
+        }
+        CheckKind::DnsCompare => {
+            check_dns(config, check, test);
+            test.duration_ms = start.elapsed().as_millis() as u64;
+        }
+    }
+
+    Ok(())
+}
+
+/// Pass if the status code is the expected one, or any 2xx by default
+fn check_status(client: &Agent, test: &mut TestReport, expected: Option<u16>) {
+    match client.get(&test.url).call() {
+        Ok(response) => {
+            let status = response.status();
+            test.status_code = Some(status.as_u16());
+            test.proxy_headers = interference::find_proxy_headers(response.headers());
+
+            let matches = match expected {
+                Some(expected) => status.as_u16() == expected,
+                None => status.is_success(),
+            };
+
+            test.result = if matches {
                 TestResult::Pass
+            } else if let Some(redirect) = interference::check_redirect(status, response.headers())
+            {
+                TestResult::Intercepted(redirect)
+            } else {
+                TestResult::Fail(format!("unexpected status code {status}"))
+            };
+        }
+        Err(error) => test.set_error(error),
+    }
+}
+
+/// Connect to the `host:port` in the test's URL field
+fn check_tcp_connect(
+    config: &TargetConfig,
+    resolver: ResolverChoice,
+    banner: Option<&str>,
+    test: &mut TestReport,
+) {
+    let Some((host, port)) = test
+        .url
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.to_string(), port.parse::<u16>().ok()?)))
+    else {
+        test.result = TestResult::Error("host must be host:port".to_string());
+        return;
+    };
+
+    let addresses = match resolve_host(config, resolver, &host) {
+        Ok(addresses) if addresses.is_empty() => {
+            test.error_kind = Some(ErrorKind::HostNotFound);
+            test.result = TestResult::Error("host not found".to_string());
+            return;
+        }
+        Ok(addresses) => addresses,
+        Err(error) => {
+            test.set_io_error(error);
+            return;
+        }
+    };
+    test.addresses = addresses.clone();
+
+    let mut last_error = None;
+
+    for address in addresses {
+        match TcpStream::connect_timeout(&SocketAddr::new(address, port), TCP_TIMEOUT) {
+            Ok(stream) => {
+                match banner {
+                    Some(banner) => check_banner(stream, banner, test),
+                    None => test.result = TestResult::Pass,
+                }
+                return;
+            }
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    if let Some(error) = last_error {
+        // Include the port so blocked ports can be told apart in the message
+        test.set_io_error(std::io::Error::new(
+            error.kind(),
+            format!("port {port}: {error}"),
+        ));
+    }
+}
+
+/// Read the first line the server sends and check that it starts with the banner
+fn check_banner(mut stream: TcpStream, banner: &str, test: &mut TestReport) {
+    if let Err(error) = stream.set_read_timeout(Some(TCP_TIMEOUT)) {
+        test.set_io_error(error);
+        return;
+    }
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 256];
+
+    while received.len() < banner.len().max(SNIPPET_LENGTH) && !received.contains(&b'\n') {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(amount) => received.extend_from_slice(&buf[..amount]),
+            Err(error) => {
+                test.set_io_error(error);
+                return;
             }
         }
-        Err(error) => TestResult::Error(Box::new(error)),
+    }
+
+    if received.starts_with(banner.as_bytes()) {
+        test.result = TestResult::Pass;
+    } else {
+        let mut snippet = received.escape_ascii().to_string();
+        snippet.truncate(SNIPPET_LENGTH);
+
+        let mut message_snippet = snippet.clone();
+        message_snippet.truncate(64);
+
+        test.result = if received.is_empty() {
+            TestResult::Fail("connection closed without a banner".to_string())
+        } else {
+            TestResult::Fail(format!("unexpected banner '{message_snippet}'"))
+        };
+        test.snippet = Some(snippet);
+    }
+}
+
+/// Returns the addresses of the host name or IP address (which may be in brackets)
+fn resolve_host(
+    config: &TargetConfig,
+    resolver: ResolverChoice,
+    host: &str,
+) -> std::io::Result<Vec<IpAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if let Ok(address) = host.parse::<IpAddr>() {
+        return Ok(vec![address]);
+    }
+
+    match resolver {
+        ResolverChoice::System => Ok((host, 0)
+            .to_socket_addrs()?
+            .map(|address| address.ip())
+            .collect()),
+        ResolverChoice::Bootstrap => bootstrap_resolver(config)
+            .lookup(host, config.dns_timeout())
+            .map(|(_server, addresses)| addresses),
     }
 }
@@ -1,15 +1,322 @@
-use std::{net::IpAddr, path::Path};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
 
+use anyhow::Context;
 use serde::Deserialize;
 
 /// target.json config object
 #[derive(Deserialize)]
 pub struct TargetConfig {
     pub bootstrap_dns: Vec<IpAddr>,
-    pub nonexistent_url: String,
-    pub cleartext_url: String,
-    pub target_url: String,
-    pub content: String,
+    /// Older form of the `nonexistent` check
+    #[serde(default)]
+    pub nonexistent_url: Option<String>,
+    /// Older form of the `cleartext` check
+    #[serde(default)]
+    pub cleartext_url: Option<String>,
+    /// Older form of the `target` and `tls` checks
+    #[serde(default)]
+    pub target_url: Option<String>,
+    /// Expected body of `cleartext_url` and `target_url`
+    #[serde(default)]
+    pub content: Option<String>,
+    /// Older form of the `names` of the `dns` check
+    #[serde(default)]
+    pub dns_check_names: Vec<String>,
+    /// Older form of the `spki_pins` of the `tls` check
+    #[serde(default)]
+    pub tls_spki_pins: Vec<String>,
+    /// Older form of the `issuers` of the `tls` check
+    #[serde(default)]
+    pub tls_issuers: Vec<String>,
+    /// Checks run in order after the ones made from the older fields
+    #[serde(default)]
+    pub checks: Vec<CheckConfig>,
+    /// Port of the bootstrap DNS servers
+    #[serde(default = "default_dns_port")]
+    pub bootstrap_dns_port: u16,
+    /// Resolvers the DNS test compares as the system's instead of the ones in resolv.conf
+    #[serde(default)]
+    pub system_dns: Option<Vec<SocketAddr>>,
+    /// Object downloaded by `--measure` instead of `target_url`
+    #[serde(default)]
+    pub measure_url: Option<String>,
+    /// Stop the `--measure` download after this many bytes
+    #[serde(default = "default_measure_max_bytes")]
+    pub measure_max_bytes: u64,
+    /// Timeout of a DNS lookup in milliseconds
+    #[serde(default = "default_dns_timeout_ms")]
+    pub dns_timeout_ms: u64,
+    /// Timeout of an HTTP request in milliseconds
+    #[serde(default = "default_http_timeout_ms")]
+    pub http_timeout_ms: u64,
+}
+
+impl TargetConfig {
+    /// Returns the bootstrap DNS servers with their port
+    pub fn bootstrap_servers(&self) -> Vec<SocketAddr> {
+        self.bootstrap_dns
+            .iter()
+            .map(|address| SocketAddr::new(*address, self.bootstrap_dns_port))
+            .collect()
+    }
+
+    pub fn dns_timeout(&self) -> Duration {
+        Duration::from_millis(self.dns_timeout_ms)
+    }
+
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_millis(self.http_timeout_ms)
+    }
+
+    /// Returns the object downloaded by `--measure`
+    pub fn measurement_url(&self) -> anyhow::Result<&str> {
+        self.measure_url
+            .as_deref()
+            .or(self.target_url.as_deref())
+            .context("measure_url is not set")
+    }
+
+    /// Returns the checks made from the older fields followed by `checks`
+    pub fn all_checks(&self) -> Vec<CheckConfig> {
+        let mut checks = Vec::new();
+
+        if let Some(url) = &self.nonexistent_url {
+            checks.push(CheckConfig {
+                url: Some(url.clone()),
+                resolver: ResolverChoice::Bootstrap,
+                ..CheckConfig::new("nonexistent", CheckKind::NonexistentDomain)
+            });
+        }
+
+        if let Some(url) = &self.cleartext_url {
+            checks.push(CheckConfig {
+                url: Some(url.clone()),
+                content: self.content.clone(),
+                resolver: ResolverChoice::Bootstrap,
+                families: true,
+                proxy_header_baseline: self.target_url.as_ref().map(|_| "target".to_string()),
+                ..CheckConfig::new("cleartext", CheckKind::ContentMatch)
+            });
+        }
+
+        if let Some(url) = &self.target_url {
+            checks.push(CheckConfig {
+                url: Some(url.clone()),
+                content: self.content.clone(),
+                families: true,
+                ..CheckConfig::new("target", CheckKind::ContentMatch)
+            });
+
+            if url.starts_with("https:") {
+                checks.push(CheckConfig {
+                    url: Some(url.clone()),
+                    spki_pins: self.tls_spki_pins.clone(),
+                    issuers: self.tls_issuers.clone(),
+                    ..CheckConfig::new("tls", CheckKind::TlsCertificate)
+                });
+            }
+        }
+
+        let mut names = Vec::new();
+        for name in [&self.cleartext_url, &self.target_url]
+            .into_iter()
+            .flatten()
+            .map(|url| url_host(url))
+            .chain(self.dns_check_names.iter().cloned())
+        {
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+
+        if self.nonexistent_url.is_some() || !names.is_empty() {
+            checks.push(CheckConfig {
+                url: self.nonexistent_url.clone(),
+                names,
+                required: false,
+                ..CheckConfig::new("dns", CheckKind::DnsCompare)
+            });
+        }
+
+        checks.extend(self.checks.iter().cloned());
+
+        checks
+    }
+}
+
+/// A test of the network check
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckConfig {
+    pub name: String,
+    pub kind: CheckKind,
+    /// URL for the HTTP and TLS checks; `{random}` is replaced for nonexistent-domain and dns-compare
+    #[serde(default)]
+    pub url: Option<String>,
+    /// `host:port` for tcp-connect or a domain name for dns-resolve
+    #[serde(default)]
+    pub host: Option<String>,
+    /// Expected body for content-match
+    #[serde(default)]
+    pub content: Option<String>,
+    /// Expected hex encoded SHA-256 hash of the body for content-match
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Text the server must send first for tcp-connect, such as `@RSYNCD:`
+    #[serde(default)]
+    pub banner: Option<String>,
+    /// Expected status code for status-only (any 2xx by default)
+    #[serde(default)]
+    pub status: Option<u16>,
+    /// Names that dns-compare looks up with every resolver
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// Expected `sha256/<base64>` hashes of a public key in the certificate chain for tls-certificate
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+    /// Expected issuer names of the certificate for tls-certificate such as `Let's Encrypt`
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    /// Repeat the check over IPv4 only and IPv6 only
+    #[serde(default)]
+    pub families: bool,
+    /// Check whose response is compared for proxy headers that only this check's response has
+    #[serde(default)]
+    pub proxy_header_baseline: Option<String>,
+    #[serde(default)]
+    pub resolver: ResolverChoice,
+    /// Whether a failure fails the whole network check
+    #[serde(default = "default_required")]
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CheckKind {
+    /// The URL's host must not resolve
+    NonexistentDomain,
+    /// The URL must return the expected content or hash
+    ContentMatch,
+    /// The URL must return the expected status code
+    StatusOnly,
+    /// A TCP connection to the host must succeed and send the banner if set
+    TcpConnect,
+    /// The host must resolve to at least one address
+    DnsResolve,
+    /// The URL's certificate chain must match the pins or issuers if set
+    TlsCertificate,
+    /// The bootstrap and system resolvers must give the same answers
+    DnsCompare,
+}
+
+/// DNS servers used to resolve the host of a check
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolverChoice {
+    /// The system's resolver (dnscrypt-proxy on the appliance)
+    #[default]
+    System,
+    /// The `bootstrap_dns` servers
+    Bootstrap,
+}
+
+impl CheckConfig {
+    fn new(name: &str, kind: CheckKind) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            url: None,
+            host: None,
+            content: None,
+            sha256: None,
+            banner: None,
+            status: None,
+            names: Vec::new(),
+            spki_pins: Vec::new(),
+            issuers: Vec::new(),
+            families: false,
+            proxy_header_baseline: None,
+            resolver: ResolverChoice::default(),
+            required: true,
+        }
+    }
+
+    /// Returns the URL or host that the check connects to
+    ///
+    /// The URL of dns-compare is optional, so its target is empty.
+    pub fn target(&self) -> anyhow::Result<&str> {
+        let name = &self.name;
+
+        match self.kind {
+            CheckKind::NonexistentDomain
+            | CheckKind::ContentMatch
+            | CheckKind::StatusOnly
+            | CheckKind::TlsCertificate => self
+                .url
+                .as_deref()
+                .with_context(|| format!("check {name} requires url")),
+            CheckKind::TcpConnect | CheckKind::DnsResolve => self
+                .host
+                .as_deref()
+                .with_context(|| format!("check {name} requires host")),
+            CheckKind::DnsCompare => Ok(""),
+        }
+    }
+
+    /// Returns an error if a field needed by the kind is missing
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let name = &self.name;
+
+        self.target()?;
+
+        if self.kind == CheckKind::ContentMatch {
+            anyhow::ensure!(
+                self.content.is_some() || self.sha256.is_some(),
+                "check {name} requires content or sha256"
+            );
+        }
+
+        anyhow::ensure!(
+            !self.families || matches!(self.kind, CheckKind::ContentMatch | CheckKind::StatusOnly),
+            "check {name} can only set families for content-match or status-only"
+        );
+
+        Ok(())
+    }
+}
+
+/// Returns the domain of the URL or an empty string if it is an IP address
+pub(crate) fn url_host(url: &str) -> String {
+    url::Url::parse(url)
+        .ok()
+        .and_then(|url| match url.host() {
+            Some(url::Host::Domain(domain)) => Some(domain.to_string()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_dns_port() -> u16 {
+    53
+}
+
+fn default_measure_max_bytes() -> u64 {
+    25_000_000
+}
+
+fn default_dns_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_http_timeout_ms() -> u64 {
+    30000
 }
 
 /// Deserialize the config from the given path.
@@ -17,5 +324,9 @@ pub fn load_config(path: &Path) -> anyhow::Result<TargetConfig> {
     let config_text = std::fs::read_to_string(path)?;
     let config = serde_json::from_str::<TargetConfig>(&config_text)?;
 
+    for check in config.all_checks() {
+        check.validate()?;
+    }
+
     Ok(config)
 }
@@ -0,0 +1,313 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use dnsclient::{
+    UpstreamServer,
+    reexports::dnssector::{
+        self, DNSIterable, DNSSector, RdataIterable,
+        constants::{Class, Type},
+    },
+    sync::DNSClient,
+};
+use serde::Serialize;
+
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Answer from a single DNS server for the A and AAAA records of a name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "answer", rename_all = "snake_case")]
+pub enum DnsAnswer {
+    /// The name exists (the list is empty if it has no addresses)
+    Addresses {
+        addresses: Vec<IpAddr>,
+    },
+    /// The server said the name does not exist
+    NxDomain,
+    /// The server did not answer within the timeout
+    NoResponse,
+    Error {
+        message: String,
+    },
+}
+
+impl DnsAnswer {
+    pub fn addresses(&self) -> &[IpAddr] {
+        match self {
+            DnsAnswer::Addresses { addresses } => addresses,
+            _ => &[],
+        }
+    }
+}
+
+/// Query the server for the addresses of the name
+pub fn resolve(server: SocketAddr, name: &str, timeout: Duration) -> DnsAnswer {
+    let mut client = DNSClient::new(vec![UpstreamServer::new(server)]);
+    client.set_timeout(timeout);
+
+    let mut addresses = Vec::new();
+
+    for rr_type in ["A", "AAAA"] {
+        match query(&client, name, rr_type) {
+            Ok(Some(answer)) => addresses.extend(answer),
+            Ok(None) => return DnsAnswer::NxDomain,
+            Err(error) if error.to_string().contains("No response") => {
+                return DnsAnswer::NoResponse;
+            }
+            Err(error) => {
+                return DnsAnswer::Error {
+                    message: error.to_string(),
+                };
+            }
+        }
+    }
+
+    addresses.sort();
+    addresses.dedup();
+
+    DnsAnswer::Addresses { addresses }
+}
+
+/// Returns the addresses or `None` if the name does not exist
+fn query(client: &DNSClient, name: &str, rr_type: &str) -> std::io::Result<Option<Vec<IpAddr>>> {
+    let to_io_error = |error: dnssector::Error| std::io::Error::other(error.to_string());
+
+    let packet = dnssector::r#gen::query(
+        name.as_bytes(),
+        Type::from_string(rr_type).unwrap(),
+        Class::from_string("IN").unwrap(),
+    )
+    .map_err(to_io_error)?
+    .into_packet();
+
+    let response = client.query_raw(&packet, true)?;
+    let mut response = DNSSector::new(response)
+        .map_err(to_io_error)?
+        .parse()
+        .map_err(to_io_error)?;
+
+    if response.rcode() == RCODE_NXDOMAIN {
+        return Ok(None);
+    }
+
+    let mut addresses = Vec::new();
+    let mut iter = response.into_iter_answer();
+
+    while let Some(item) = iter {
+        if let Ok(address) = item.rr_ip() {
+            addresses.push(address);
+        }
+        iter = item.next();
+    }
+
+    Ok(Some(addresses))
+}
+
+/// A DNS server to compare with the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolver {
+    pub address: SocketAddr,
+    /// Whether it is the system's resolver (from resolv.conf)
+    pub system: bool,
+}
+
+/// Answer of a resolver for a name
+#[derive(Debug, Clone, Serialize)]
+pub struct DnsResult {
+    pub name: String,
+    pub resolver: SocketAddr,
+    pub system: bool,
+    #[serde(flatten)]
+    pub answer: DnsAnswer,
+}
+
+/// A problem found by comparing the resolvers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "finding", rename_all = "snake_case")]
+pub enum DnsFinding {
+    /// A name that does not exist was resolved to addresses
+    NxdomainRewritten {
+        resolver: SocketAddr,
+        name: String,
+        addresses: Vec<IpAddr>,
+    },
+    /// A name that other resolvers answer was said to not exist
+    Blocked { resolver: SocketAddr, name: String },
+    /// The addresses have nothing in common with the other resolvers' addresses
+    Mismatch {
+        resolver: SocketAddr,
+        name: String,
+        addresses: Vec<IpAddr>,
+        expected: Vec<IpAddr>,
+    },
+    /// The resolver did not answer any query
+    NoResponse { resolver: SocketAddr },
+}
+
+impl DnsFinding {
+    /// Returns whether the finding means DNS is being tampered with
+    pub fn is_interference(&self) -> bool {
+        matches!(
+            self,
+            DnsFinding::NxdomainRewritten { .. } | DnsFinding::Blocked { .. }
+        )
+    }
+}
+
+impl Display for DnsFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsFinding::NxdomainRewritten {
+                resolver,
+                name,
+                addresses,
+            } => write!(
+                f,
+                "{resolver} resolved nonexistent {name} to {}",
+                join(addresses)
+            ),
+            DnsFinding::Blocked { resolver, name } => {
+                write!(f, "{resolver} said {name} does not exist")
+            }
+            DnsFinding::Mismatch {
+                resolver,
+                name,
+                addresses,
+                expected,
+            } => write!(
+                f,
+                "{resolver} resolved {name} to {} instead of {}",
+                join(addresses),
+                join(expected)
+            ),
+            DnsFinding::NoResponse { resolver } => write!(f, "{resolver} did not respond"),
+        }
+    }
+}
+
+fn join(addresses: &[IpAddr]) -> String {
+    addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Resolve every name with every resolver in parallel
+pub fn resolve_all(resolvers: &[Resolver], names: &[String], timeout: Duration) -> Vec<DnsResult> {
+    std::thread::scope(|scope| {
+        let handles = resolvers
+            .iter()
+            .flat_map(|resolver| names.iter().map(move |name| (resolver, name)))
+            .map(|(resolver, name)| {
+                scope.spawn(move || DnsResult {
+                    name: name.clone(),
+                    resolver: resolver.address,
+                    system: resolver.system,
+                    answer: resolve(resolver.address, name, timeout),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+/// Compare the answers of the resolvers
+///
+/// Answers for real names are compared with the system resolver's answers,
+/// or the other resolvers' answers for the system resolver itself. Only
+/// answers with no addresses in common are reported because servers for
+/// large sites give different addresses depending on location.
+pub fn compare(results: &[DnsResult], nonexistent_name: &str) -> Vec<DnsFinding> {
+    let mut findings = Vec::new();
+
+    let mut resolvers = results
+        .iter()
+        .map(|result| result.resolver)
+        .collect::<Vec<_>>();
+    resolvers.dedup();
+
+    for resolver in resolvers {
+        let no_response = results
+            .iter()
+            .filter(|result| result.resolver == resolver)
+            .all(|result| result.answer == DnsAnswer::NoResponse);
+
+        if no_response {
+            findings.push(DnsFinding::NoResponse { resolver });
+        }
+    }
+
+    let mut rewriting = Vec::new();
+
+    for result in results {
+        if result.name == nonexistent_name && !result.answer.addresses().is_empty() {
+            rewriting.push(result.resolver);
+            findings.push(DnsFinding::NxdomainRewritten {
+                resolver: result.resolver,
+                name: result.name.clone(),
+                addresses: result.answer.addresses().to_vec(),
+            });
+        }
+    }
+
+    for result in results {
+        if result.name == nonexistent_name || rewriting.contains(&result.resolver) {
+            continue;
+        }
+
+        // Resolvers that rewrite NXDOMAIN can't be trusted as a reference
+        let others = results.iter().filter(|other| {
+            other.name == result.name
+                && other.resolver != result.resolver
+                && !rewriting.contains(&other.resolver)
+        });
+        let references = others
+            .clone()
+            .filter(|other| other.system && !result.system)
+            .flat_map(|other| other.answer.addresses())
+            .collect::<Vec<_>>();
+        let references = if references.is_empty() {
+            others.flat_map(|other| other.answer.addresses()).collect()
+        } else {
+            references
+        };
+
+        if references.is_empty() {
+            continue;
+        }
+
+        match &result.answer {
+            DnsAnswer::NxDomain => findings.push(DnsFinding::Blocked {
+                resolver: result.resolver,
+                name: result.name.clone(),
+            }),
+            DnsAnswer::Addresses { addresses }
+                if !addresses.is_empty()
+                    && !addresses
+                        .iter()
+                        .any(|address| references.contains(&address)) =>
+            {
+                let mut expected = references.into_iter().copied().collect::<Vec<_>>();
+                expected.sort();
+                expected.dedup();
+
+                findings.push(DnsFinding::Mismatch {
+                    resolver: result.resolver,
+                    name: result.name.clone(),
+                    addresses: addresses.clone(),
+                    expected,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    findings
+}
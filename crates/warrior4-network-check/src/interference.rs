@@ -0,0 +1,138 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use ureq::http::{HeaderMap, StatusCode};
+
+/// Response headers added by caching or transparent proxies
+const PROXY_HEADERS: &[&str] = &[
+    "via",
+    "x-cache",
+    "x-cache-lookup",
+    "x-squid-error",
+    "x-bluecoat-via",
+    "proxy-connection",
+];
+
+/// A recognized pattern of a network modifying HTTP traffic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Interference {
+    /// Redirect to another page, usually a captive portal login page
+    Redirect {
+        status_code: u16,
+        location: Option<String>,
+    },
+    /// The page was replaced or had HTML inserted into it
+    InjectedContent,
+    /// Headers that proxies add were found
+    ProxyHeaders { headers: Vec<String> },
+    /// Content-Length is not the length of the expected content
+    ContentLengthMismatch { expected: u64, actual: u64 },
+    /// The certificate chain does not match the expected pins or issuers
+    TlsInterception { issuer: String, trusted: bool },
+}
+
+impl Display for Interference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interference::Redirect {
+                status_code,
+                location,
+            } => write!(
+                f,
+                "redirected ({status_code}) to {}",
+                location.as_deref().unwrap_or("unknown location")
+            ),
+            Interference::InjectedContent => write!(f, "content was replaced or injected"),
+            Interference::ProxyHeaders { headers } => {
+                write!(f, "proxy headers found: {}", headers.join(", "))
+            }
+            Interference::ContentLengthMismatch { expected, actual } => {
+                write!(f, "content length is {actual} instead of {expected}")
+            }
+            Interference::TlsInterception { issuer, trusted } => write!(
+                f,
+                "TLS interception: certificate issued by '{issuer}' does not match the expected pins or issuers{}",
+                if *trusted {
+                    ""
+                } else {
+                    " and is not publicly trusted"
+                }
+            ),
+        }
+    }
+}
+
+/// Returns a redirect interference if the status code is a redirect
+pub fn check_redirect(status: StatusCode, headers: &HeaderMap) -> Option<Interference> {
+    if status.is_redirection() {
+        let location = headers
+            .get("location")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        Some(Interference::Redirect {
+            status_code: status.as_u16(),
+            location,
+        })
+    } else {
+        None
+    }
+}
+
+/// Returns the proxy related headers as `name: value` strings
+pub fn find_proxy_headers(headers: &HeaderMap) -> Vec<String> {
+    let mut found = Vec::new();
+
+    for name in PROXY_HEADERS {
+        for value in headers.get_all(*name) {
+            found.push(format!(
+                "{name}: {}",
+                String::from_utf8_lossy(value.as_bytes())
+            ));
+        }
+    }
+
+    found
+}
+
+/// Returns the Content-Length header value
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Classify a body that is not the expected content
+pub fn classify_content(
+    content: &[u8],
+    expected_content: &[u8],
+    declared_length: Option<u64>,
+) -> Option<Interference> {
+    let lowercase = content.to_ascii_lowercase();
+    let looks_like_html = [&b"<html"[..], b"<script", b"<iframe", b"<meta"]
+        .iter()
+        .any(|tag| contains(&lowercase, tag));
+
+    if contains(content, expected_content) || looks_like_html {
+        return Some(Interference::InjectedContent);
+    }
+
+    match declared_length {
+        Some(actual) if actual != expected_content.len() as u64 => {
+            Some(Interference::ContentLengthMismatch {
+                expected: expected_content.len() as u64,
+                actual,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    !needle.is_empty()
+        && haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
@@ -0,0 +1,8 @@
+//! Checks the network for any possible packet inspection interference
+mod adapter;
+pub mod check;
+pub mod config;
+pub mod dns;
+pub mod interference;
+pub mod measure;
+pub mod tls;
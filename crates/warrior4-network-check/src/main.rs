@@ -1,11 +1,8 @@
 use std::path::PathBuf;
 
 use anyhow::Context;
-use clap::Parser;
-
-mod adapter;
-mod check;
-mod config;
+use clap::{Parser, ValueEnum};
+use warrior4_network_check::{check, config, measure};
 
 // Command line arguments
 #[derive(Parser, Debug)]
@@ -17,6 +14,22 @@ struct Args {
         default_value = "/usr/share/warrior4-network-check/target.json"
     )]
     target_config: PathBuf,
+
+    /// Format of the report printed to stdout
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Measure the latency and download speed instead of checking for interference
+    #[arg(long)]
+    measure: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// Only print progress to stderr
+    Text,
+    /// Also print the report as JSON to stdout
+    Json,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -25,8 +38,16 @@ fn main() -> anyhow::Result<()> {
     let config =
         config::load_config(&args.target_config).context("loading target config failed")?;
 
+    if args.measure {
+        return run_measure(&config, args.format);
+    }
+
     match check::check_network(&config) {
         Ok(report) => {
+            if args.format == Format::Json {
+                println!("{}", serde_json::to_string(&report)?);
+            }
+
             if !report.is_pass() {
                 anyhow::bail!("check failed")
             } else {
@@ -36,3 +57,27 @@ fn main() -> anyhow::Result<()> {
         Err((_report, error)) => Err(error.into()),
     }
 }
+
+fn run_measure(config: &config::TargetConfig, format: Format) -> anyhow::Result<()> {
+    let url = config.measurement_url()?;
+    eprint!("Measure download ({url}) ... ");
+    let measurement = measure::measure(config);
+
+    match &measurement {
+        Ok(measurement) => eprintln!("{measurement}"),
+        Err(error) => eprintln!("{error:#}"),
+    }
+
+    let measurement = measurement?;
+
+    if format == Format::Json {
+        let report = check::Report {
+            passed: true,
+            measurement: Some(Box::new(measurement)),
+            ..Default::default()
+        };
+        println!("{}", serde_json::to_string(&report)?);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use std::{
+    fmt::Display,
+    io::Read,
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use ureq::{
+    Agent,
+    unversioned::{
+        resolver::{ResolvedSocketAddrs, Resolver},
+        transport::DefaultConnector,
+    },
+};
+
+use crate::config::TargetConfig;
+
+/// Stop downloading after this long even if the object isn't finished
+const MAX_DOWNLOAD_DURATION: Duration = Duration::from_secs(15);
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Latency and download speed to the measurement URL
+#[derive(Debug, Default, Serialize)]
+pub struct Measurement {
+    pub url: String,
+    /// Address that was connected to
+    pub address: Option<IpAddr>,
+    /// Time to resolve the host name or `None` if the host is an IP address
+    pub dns_ms: Option<u64>,
+    /// Time to open a TCP connection
+    pub connect_ms: u64,
+    /// Time from sending the request until the response headers arrived,
+    /// including connecting again and the TLS handshake
+    pub first_byte_ms: u64,
+    /// Time from sending the request until the download stopped
+    pub total_ms: u64,
+    /// Size of the body that was downloaded
+    pub bytes: u64,
+    /// Whether the download stopped at the size or time limit
+    pub truncated: bool,
+    /// Download speed of the body
+    pub bytes_per_second: u64,
+}
+
+impl Measurement {
+    pub fn megabits_per_second(&self) -> f64 {
+        self.bytes_per_second as f64 * 8.0 / 1_000_000.0
+    }
+}
+
+impl Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(dns_ms) = self.dns_ms {
+            write!(f, "DNS {dns_ms} ms, ")?;
+        }
+
+        write!(
+            f,
+            "connect {} ms, first byte {} ms, {:.1} Mbit/s ({} bytes in {} ms)",
+            self.connect_ms,
+            self.first_byte_ms,
+            self.megabits_per_second(),
+            self.bytes,
+            self.total_ms
+        )
+    }
+}
+
+/// Resolver that returns addresses that were already looked up
+#[derive(Debug)]
+struct FixedResolver {
+    addresses: Vec<IpAddr>,
+}
+
+impl Resolver for FixedResolver {
+    fn resolve(
+        &self,
+        uri: &ureq::http::Uri,
+        _config: &ureq::config::Config,
+        _timeout: ureq::unversioned::transport::NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let port = uri
+            .port_u16()
+            .or_else(|| match uri.scheme_str() {
+                Some("https") => Some(443),
+                Some("http") => Some(80),
+                _ => None,
+            })
+            .ok_or_else(|| ureq::Error::BadUri("port".to_string()))?;
+
+        let mut socket_addresses = self.empty();
+
+        for address in self.addresses.iter().take(16) {
+            socket_addresses.push(SocketAddr::new(*address, port));
+        }
+
+        Ok(socket_addresses)
+    }
+}
+
+/// Download the measurement object and time each step
+pub fn measure(config: &TargetConfig) -> anyhow::Result<Measurement> {
+    let url = config.measurement_url()?.to_string();
+    let parsed_url = url::Url::parse(&url).context("invalid measurement URL")?;
+    let host = parsed_url
+        .host_str()
+        .context("URL has no host")?
+        .to_string();
+    let port = parsed_url
+        .port_or_known_default()
+        .context("URL has no port")?;
+
+    let mut measurement = Measurement {
+        url,
+        ..Default::default()
+    };
+
+    let addresses = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(address) => vec![address],
+        Err(_) => {
+            let start = Instant::now();
+            let addresses = (host.as_str(), port)
+                .to_socket_addrs()
+                .context("DNS lookup failed")?
+                .map(|address| address.ip())
+                .collect::<Vec<_>>();
+            measurement.dns_ms = Some(start.elapsed().as_millis() as u64);
+            addresses
+        }
+    };
+
+    let address = *addresses.first().context("host has no addresses")?;
+    measurement.address = Some(address);
+
+    let start = Instant::now();
+    TcpStream::connect_timeout(&SocketAddr::new(address, port), config.http_timeout())
+        .context("TCP connection failed")?;
+    measurement.connect_ms = start.elapsed().as_millis() as u64;
+
+    let agent = Agent::with_parts(
+        Agent::config_builder()
+            .timeout_connect(Some(config.http_timeout()))
+            .timeout_recv_response(Some(config.http_timeout()))
+            .timeout_recv_body(Some(MAX_DOWNLOAD_DURATION + config.http_timeout()))
+            .build(),
+        DefaultConnector::new(),
+        FixedResolver {
+            addresses: vec![address],
+        },
+    );
+
+    let start = Instant::now();
+    let mut response = agent
+        .get(&measurement.url)
+        .call()
+        .context("request failed")?;
+    measurement.first_byte_ms = start.elapsed().as_millis() as u64;
+
+    let mut reader = response.body_mut().as_reader();
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+
+    loop {
+        if measurement.bytes >= config.measure_max_bytes || start.elapsed() >= MAX_DOWNLOAD_DURATION
+        {
+            measurement.truncated = true;
+            break;
+        }
+
+        let amount = reader.read(&mut buf).context("download failed")?;
+
+        if amount == 0 {
+            break;
+        }
+
+        measurement.bytes += amount as u64;
+    }
+
+    let total = start.elapsed();
+    measurement.total_ms = total.as_millis() as u64;
+
+    let download_time = total.saturating_sub(Duration::from_millis(measurement.first_byte_ms));
+    measurement.bytes_per_second =
+        (measurement.bytes as f64 / download_time.as_secs_f64().max(0.001)) as u64;
+
+    Ok(measurement)
+}
@@ -0,0 +1,237 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Object identifiers of the name attributes shown in certificate names
+const NAME_ATTRIBUTES: &[(&[u8], &str)] = &[
+    (&[0x55, 0x04, 0x03], "CN"),
+    (&[0x55, 0x04, 0x0a], "O"),
+    (&[0x55, 0x04, 0x0b], "OU"),
+    (&[0x55, 0x04, 0x06], "C"),
+];
+
+/// A certificate presented by the server
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    /// Hash of the public key in the `sha256/<base64>` pin format
+    pub spki_pin: String,
+}
+
+/// Certificate chain presented by the server
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CertificateChain {
+    pub certificates: Vec<CertificateInfo>,
+    /// Whether the chain is valid with the public root certificates
+    pub trusted: bool,
+}
+
+impl CertificateChain {
+    /// Returns the issuer of the server's own certificate
+    pub fn issuer(&self) -> &str {
+        self.certificates
+            .first()
+            .map(|certificate| certificate.issuer.as_str())
+            .unwrap_or_default()
+    }
+
+    /// Returns whether any certificate has one of the pins, or the server's
+    /// certificate was issued by one of the issuers
+    ///
+    /// Issuer names match if they are part of the formatted issuer such as
+    /// `CN=R11, O=Let's Encrypt, C=US`.
+    pub fn matches(&self, spki_pins: &[String], issuers: &[String]) -> bool {
+        let pin_matches = self
+            .certificates
+            .iter()
+            .any(|certificate| spki_pins.contains(&certificate.spki_pin));
+        let issuer_matches = issuers
+            .iter()
+            .any(|issuer| !issuer.is_empty() && self.issuer().contains(issuer.as_str()));
+
+        pin_matches || issuer_matches
+    }
+}
+
+/// Verifier that accepts any certificate so the chain can be inspected, but
+/// remembers whether it would have been trusted
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    trusted: AtomicBool,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let trusted = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .is_ok();
+        self.trusted.store(trusted, Ordering::Relaxed);
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Connect to the server and return the certificate chain it presents
+pub fn fetch_chain(host: &str, port: u16, timeout: Duration) -> std::io::Result<CertificateChain> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(std::io::Error::other)?;
+    let verifier = Arc::new(RecordingVerifier {
+        inner,
+        trusted: AtomicBool::new(false),
+    });
+
+    let config = ClientConfig::builder_with_provider(provider as Arc<CryptoProvider>)
+        .with_safe_default_protocol_versions()
+        .map_err(std::io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+
+    let server_name = ServerName::try_from(host.to_string()).map_err(std::io::Error::other)?;
+    let mut connection =
+        ClientConnection::new(Arc::new(config), server_name).map_err(std::io::Error::other)?;
+
+    let address = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("failed to lookup address information"))?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    while connection.is_handshaking() {
+        connection.complete_io(&mut stream)?;
+    }
+
+    let certificates = connection
+        .peer_certificates()
+        .unwrap_or_default()
+        .iter()
+        .map(certificate_info)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok(CertificateChain {
+        certificates,
+        trusted: verifier.trusted.load(Ordering::Relaxed),
+    })
+}
+
+fn certificate_info(der: &CertificateDer<'_>) -> std::io::Result<CertificateInfo> {
+    let certificate = webpki::EndEntityCert::try_from(der).map_err(std::io::Error::other)?;
+    let spki = certificate.subject_public_key_info();
+
+    Ok(CertificateInfo {
+        subject: format_name(certificate.subject()),
+        issuer: format_name(certificate.issuer()),
+        spki_pin: format!("sha256/{}", BASE64_STANDARD.encode(Sha256::digest(&spki))),
+    })
+}
+
+/// Format a DER encoded X.509 name (without the outer sequence) like `CN=name, O=organization`
+fn format_name(mut der: &[u8]) -> String {
+    let mut parts = Vec::new();
+
+    // Name is a sequence of sets of (type, value) sequences
+    while let Some((set, rest)) = read_der(der) {
+        der = rest;
+        let mut set = set;
+
+        while let Some((attribute, rest)) = read_der(set) {
+            set = rest;
+
+            let Some((oid, attribute)) = read_der(attribute) else {
+                continue;
+            };
+            let Some((value, _rest)) = read_der(attribute) else {
+                continue;
+            };
+
+            if let Some((_oid, label)) = NAME_ATTRIBUTES.iter().find(|(known, _)| *known == oid) {
+                parts.push(format!("{label}={}", String::from_utf8_lossy(value)));
+            }
+        }
+    }
+
+    parts.join(", ")
+}
+
+/// Split off a DER element and returns its contents and the remaining bytes
+fn read_der(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let first_length = *der.get(1)? as usize;
+
+    let (length, header_length) = if first_length < 0x80 {
+        (first_length, 2)
+    } else {
+        let count = first_length & 0x7f;
+
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let length = der
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, 2 + count)
+    };
+
+    let contents = der.get(header_length..header_length + length)?;
+    let rest = &der[header_length + length..];
+
+    Some((contents, rest))
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::{
+    CONTENT, DnsBehavior, WARRIOR_HOST, spawn_dns_server, spawn_http_server, target_config,
+};
+use warrior4_network_check::{
+    check::{ErrorKind, TestResult, check_network},
+    dns::DnsFinding,
+    interference::Interference,
+};
+
+#[test]
+fn clean_network_passes() {
+    let dns = spawn_dns_server(DnsBehavior::Honest);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/ok");
+
+    let report = check_network(&config).unwrap();
+
+    for test in &report.tests {
+        assert!(test.result.is_pass(), "{} {}", test.name, test.result);
+    }
+    assert!(report.passed);
+
+    let cleartext = report.test("cleartext").unwrap();
+    assert_eq!(cleartext.dns_upstream, Some(dns));
+    assert_eq!(cleartext.addresses, vec![dns.ip()]);
+}
+
+#[test]
+fn nxdomain_hijacking_fails() {
+    let dns = spawn_dns_server(DnsBehavior::HijackNxdomain);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/ok");
+
+    let report = check_network(&config).unwrap();
+
+    assert!(!report.passed);
+    assert!(matches!(
+        report.test("nonexistent").unwrap().result,
+        TestResult::Fail(_)
+    ));
+
+    let dns_test = report.test("dns").unwrap();
+    assert!(matches!(dns_test.result, TestResult::Fail(_)));
+    assert!(!dns_test.required);
+    assert!(
+        dns_test
+            .dns_findings
+            .iter()
+            .any(|finding| matches!(finding, DnsFinding::NxdomainRewritten { .. }))
+    );
+}
+
+#[test]
+fn injected_body_is_intercepted() {
+    let dns = spawn_dns_server(DnsBehavior::Honest);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/inject");
+
+    let report = check_network(&config).unwrap();
+
+    assert!(!report.passed);
+
+    let cleartext = report.test("cleartext").unwrap();
+    assert!(matches!(
+        cleartext.result,
+        TestResult::Intercepted(Interference::InjectedContent)
+    ));
+    assert!(cleartext.snippet.as_deref().unwrap().contains("<script>"));
+    assert!(report.test("target").unwrap().result.is_pass());
+}
+
+#[test]
+fn redirect_is_intercepted() {
+    let dns = spawn_dns_server(DnsBehavior::Honest);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/redirect");
+
+    let report = check_network(&config).unwrap();
+
+    assert!(!report.passed);
+
+    match &report.test("cleartext").unwrap().result {
+        TestResult::Intercepted(Interference::Redirect {
+            status_code,
+            location,
+        }) => {
+            assert_eq!(*status_code, 302);
+            assert_eq!(location.as_deref(), Some("http://login.portal.test/"));
+        }
+        result => panic!("unexpected result {result}"),
+    }
+}
+
+#[test]
+fn http_timeout_is_an_error() {
+    let dns = spawn_dns_server(DnsBehavior::Honest);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/hang");
+
+    let report = check_network(&config).unwrap();
+
+    assert!(!report.passed);
+
+    let cleartext = report.test("cleartext").unwrap();
+    assert!(matches!(cleartext.result, TestResult::Error(_)));
+    assert_eq!(cleartext.error_kind, Some(ErrorKind::Timeout));
+    assert!(report.test("target").unwrap().result.is_pass());
+}
+
+#[test]
+fn dns_timeout_is_an_error() {
+    let dns = spawn_dns_server(DnsBehavior::Silent);
+    let port = spawn_http_server();
+    let config = target_config(dns, port, "/ok");
+
+    let report = check_network(&config).unwrap();
+
+    assert!(!report.passed);
+
+    let cleartext = report.test("cleartext").unwrap();
+    assert!(matches!(cleartext.result, TestResult::Error(_)));
+    assert_eq!(cleartext.error_kind, Some(ErrorKind::Timeout));
+
+    let dns_test = report.test("dns").unwrap();
+    assert!(matches!(dns_test.result, TestResult::Error(_)));
+    assert!(
+        dns_test
+            .dns_findings
+            .iter()
+            .all(|finding| matches!(finding, DnsFinding::NoResponse { .. }))
+    );
+}
+
+#[test]
+fn configured_checks_replace_the_built_in_ones() {
+    let dns = spawn_dns_server(DnsBehavior::Honest);
+    let port = spawn_http_server();
+    let config = serde_json::from_value(serde_json::json!({
+        "bootstrap_dns": [dns.ip()],
+        "bootstrap_dns_port": dns.port(),
+        "system_dns": [dns],
+        "dns_timeout_ms": 500,
+        "http_timeout_ms": 1000,
+        "checks": [
+            {
+                "name": "cleartext",
+                "kind": "content-match",
+                "url": format!("http://{WARRIOR_HOST}:{port}/inject"),
+                "content": CONTENT,
+                "resolver": "bootstrap",
+                "required": false,
+            },
+            {
+                "name": "target",
+                "kind": "content-match",
+                "url": format!("http://127.0.0.1:{port}/ok"),
+                "content": CONTENT,
+            },
+            {"name": "no-url", "kind": "status-only", "required": false},
+        ],
+    }))
+    .unwrap();
+
+    let report = check_network(&config).unwrap();
+
+    assert!(report.passed);
+    assert_eq!(
+        report
+            .tests
+            .iter()
+            .map(|test| test.name.as_str())
+            .collect::<Vec<_>>(),
+        ["cleartext", "target", "no-url"]
+    );
+    assert!(matches!(
+        report.test("cleartext").unwrap().result,
+        TestResult::Intercepted(Interference::InjectedContent)
+    ));
+    assert!(matches!(
+        &report.test("no-url").unwrap().result,
+        TestResult::Error(message) if message.contains("requires url")
+    ));
+    assert!(report.families.iter().all(|family| family.tests.is_empty()));
+}
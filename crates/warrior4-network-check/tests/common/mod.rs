@@ -0,0 +1,159 @@
+//! Local stand-ins for the DNS and HTTP servers used by the network check
+
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::Duration,
+};
+
+use warrior4_network_check::config::TargetConfig;
+
+pub const CONTENT: &str = "<html><head></head><body>Hello.</body></html>\n";
+
+/// Name that the fake DNS server resolves to localhost
+pub const WARRIOR_HOST: &str = "warrior.test";
+
+/// How the fake DNS server answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsBehavior {
+    /// Resolve `WARRIOR_HOST` to localhost and anything else to NXDOMAIN
+    Honest,
+    /// Resolve every name to localhost like an ISP's "search" page
+    HijackNxdomain,
+    /// Never respond
+    Silent,
+}
+
+/// Start a DNS server on localhost and returns its address
+pub fn spawn_dns_server(behavior: DnsBehavior) -> SocketAddr {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let address = socket.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 512];
+
+        while let Ok((length, peer)) = socket.recv_from(&mut buf) {
+            if let Some(response) = dns_response(&buf[..length], behavior) {
+                let _ = socket.send_to(&response, peer);
+            }
+        }
+    });
+
+    address
+}
+
+fn dns_response(query: &[u8], behavior: DnsBehavior) -> Option<Vec<u8>> {
+    if behavior == DnsBehavior::Silent {
+        return None;
+    }
+
+    // Question name labels, then the type and class
+    let mut labels = Vec::new();
+    let mut end = 12;
+    while query[end] != 0 {
+        let length = query[end] as usize;
+        labels.push(String::from_utf8_lossy(&query[end + 1..end + 1 + length]).to_lowercase());
+        end += length + 1;
+    }
+    let name = labels.join(".");
+    let question = &query[12..end + 5];
+    let query_type = u16::from_be_bytes([query[end + 1], query[end + 2]]);
+
+    let exists = behavior == DnsBehavior::HijackNxdomain || name == WARRIOR_HOST;
+    let answer_count: u16 = if exists && query_type == 1 { 1 } else { 0 };
+    let flags: u16 = if exists { 0x8180 } else { 0x8183 };
+
+    let mut response = Vec::new();
+    response.extend_from_slice(&query[0..2]);
+    response.extend_from_slice(&flags.to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&answer_count.to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+
+    if answer_count > 0 {
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+    }
+
+    Some(response)
+}
+
+/// Start an HTTP server on localhost and returns its port
+///
+/// The path selects the response:
+///
+/// * `/ok`: the expected content
+/// * `/inject`: the content with a script inserted
+/// * `/redirect`: a redirect to a login page
+/// * `/hang`: never responds
+/// * anything else: 404
+pub fn spawn_http_server() -> u16 {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            std::thread::spawn(move || handle_http(stream));
+        }
+    });
+
+    port
+}
+
+fn handle_http(mut stream: TcpStream) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(length) => request.extend_from_slice(&buf[..length]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, headers, body) = match path {
+        "/ok" => ("200 OK", String::new(), CONTENT.to_string()),
+        "/inject" => (
+            "200 OK",
+            String::new(),
+            CONTENT.replace("<body>", "<body><script>ads()</script>"),
+        ),
+        "/redirect" => (
+            "302 Found",
+            "Location: http://login.portal.test/\r\n".to_string(),
+            String::new(),
+        ),
+        "/hang" => {
+            std::thread::sleep(Duration::from_secs(60));
+            return;
+        }
+        _ => ("404 Not Found", String::new(), "not found".to_string()),
+    };
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+}
+
+/// Config that only uses the local servers
+pub fn target_config(dns: SocketAddr, http_port: u16, cleartext_path: &str) -> TargetConfig {
+    let config = serde_json::json!({
+        "bootstrap_dns": [dns.ip()],
+        "bootstrap_dns_port": dns.port(),
+        "system_dns": [dns],
+        "nonexistent_url": format!("http://{{random}}.nonexistent.test:{http_port}/ok"),
+        "cleartext_url": format!("http://{WARRIOR_HOST}:{http_port}{cleartext_path}"),
+        "target_url": format!("http://{}:{http_port}/ok", IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        "content": CONTENT,
+        "dns_timeout_ms": 500,
+        "http_timeout_ms": 1000,
+    });
+
+    serde_json::from_value(config).unwrap()
+}